@@ -0,0 +1,158 @@
+use std::{env, io, process, thread};
+use std::ffi::OsString;
+use std::io::prelude::*;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use secstr::SecStr;
+
+/// Set (to the timeout in seconds) for the background copy of freepass that clears the clipboard.
+const CLEARER_ENV: &'static str = "FREEPASS_CLIPBOARD_CLEAR_AFTER";
+
+/// The clipboard program gets FREEPASS_MODE=COPY with the data on stdin,
+/// or FREEPASS_MODE=PASTE and must print the current clipboard contents.
+fn clipboard_program() -> Option<OsString> {
+    env::var_os("FREEPASS_CLIPBOARD")
+}
+
+pub fn is_available() -> bool {
+    clipboard_program().is_some()
+}
+
+/// Seconds after which the copied data is cleared, 0 means never.
+fn clear_timeout() -> u64 {
+    env::var("FREEPASS_CLIPBOARD_TIMEOUT")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(45)
+}
+
+fn run_copy(program: &OsString, data: &[u8]) -> io::Result<()> {
+    let mut process = Command::new(program)
+        .env("FREEPASS_MODE", "COPY")
+        .stdin(Stdio::piped())
+        .spawn()?;
+    process.stdin.take().unwrap().write_all(data)?; // stdin is closed when dropped here
+    process.wait()?;
+    Ok(())
+}
+
+fn run_paste(program: &OsString) -> io::Result<SecStr> {
+    let mut output = Command::new(program)
+        .env("FREEPASS_MODE", "PASTE")
+        .stdin(Stdio::null())
+        .output()?
+        .stdout;
+    if output.ends_with(b"\n") {
+        output.pop();
+    }
+    Ok(SecStr::new(output))
+}
+
+/// Clears the clipboard after the timeout, unless something else has been copied in the meantime.
+/// Returns whether it was cleared.
+fn clear_after(program: &OsString, data: &SecStr, timeout: Duration) -> io::Result<bool> {
+    thread::sleep(timeout);
+    if run_paste(program)? != *data {
+        return Ok(false);
+    }
+    run_copy(program, b"")?;
+    Ok(true)
+}
+
+/// Starts a copy of freepass that waits and clears the clipboard, so this one can exit right away.
+/// The data goes through a pipe, not the arguments or the environment.
+fn spawn_clearer(timeout: u64, data: &SecStr) -> io::Result<()> {
+    let mut process = Command::new(env::current_exe()?)
+        .env(CLEARER_ENV, timeout.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    process.stdin.take().unwrap().write_all(data.unsecure())?;
+    Ok(())
+}
+
+/// If this process was started by `spawn_clearer`, clears the clipboard and exits.
+/// Must be called before anything else in `main`.
+pub fn run_clearer_if_requested() {
+    let timeout = match env::var(CLEARER_ENV).ok().and_then(|s| s.parse::<u64>().ok()) {
+        Some(timeout) => timeout,
+        None => return,
+    };
+    let mut data = Vec::new();
+    if io::stdin().read_to_end(&mut data).is_ok() {
+        if let Some(program) = clipboard_program() {
+            let _ = clear_after(&program, &SecStr::new(data), Duration::from_secs(timeout));
+        }
+    }
+    process::exit(0);
+}
+
+/// Copies the data to the clipboard and clears it in the background after the timeout,
+/// unless something else has been copied in the meantime.
+pub fn copy(data: SecStr) {
+    let program = match clipboard_program() {
+        Some(p) => p,
+        None => {
+            eprintln!("No clipboard program, set $FREEPASS_CLIPBOARD");
+            return;
+        },
+    };
+    if let Err(e) = run_copy(&program, data.unsecure()) {
+        eprintln!("Couldn't copy to clipboard: {}", e);
+        return;
+    }
+    let timeout = clear_timeout();
+    if timeout == 0 {
+        eprintln!("Copied to clipboard!");
+        return;
+    }
+    match spawn_clearer(timeout, &data) {
+        Ok(()) => eprintln!("Copied to clipboard! Will be cleared in {} seconds.", timeout),
+        Err(e) => eprintln!("Copied to clipboard, but couldn't start clearing it: {}", e),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A clipboard program that keeps the clipboard in a file.
+    fn stub_clipboard(name: &str) -> (OsString, ::std::path::PathBuf) {
+        let dir = env::temp_dir().join(format!("freepass-clipboard-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let contents = dir.join("contents");
+        let script = dir.join("clipboard.sh");
+        fs::write(&script, format!(
+            "#!/bin/sh\nif [ \"$FREEPASS_MODE\" = \"COPY\" ]; then cat > '{0}'; else cat '{0}'; fi\n",
+            contents.display()
+        )).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o700)).unwrap();
+        (script.into_os_string(), contents)
+    }
+
+    #[test]
+    fn test_copy_and_clear() {
+        let (program, contents) = stub_clipboard("copy");
+        // The only test that touches the environment, the others use the stub directly
+        env::set_var("FREEPASS_CLIPBOARD", &program);
+        env::set_var("FREEPASS_CLIPBOARD_TIMEOUT", "0");
+        assert!(is_available());
+        copy(SecStr::from("hunter2"));
+        assert_eq!(fs::read(&contents).unwrap(), b"hunter2");
+        assert!(clear_after(&program, &SecStr::from("hunter2"), Duration::from_millis(10)).unwrap());
+        assert_eq!(fs::read(&contents).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_not_cleared_after_other_copy() {
+        let (program, contents) = stub_clipboard("other");
+        run_copy(&program, b"hunter2").unwrap();
+        fs::write(&contents, b"something else").unwrap();
+        assert!(!clear_after(&program, &SecStr::from("hunter2"), Duration::from_millis(10)).unwrap());
+        assert_eq!(fs::read(&contents).unwrap(), b"something else");
+    }
+}
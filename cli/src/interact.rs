@@ -1,4 +1,5 @@
 use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::io::{Read, Write};
 use std::fs::OpenOptions;
 use std::collections::btree_map::BTreeMap;
@@ -9,10 +10,18 @@ use interactor::*;
 use secstr::SecStr;
 use freepass_core::output::*;
 use freepass_core::data::*;
+use freepass_core::otp::OtpParams;
+use freepass_core::sqrl::*;
+use freepass_core::minisign::*;
+use freepass_core::age::*;
+use freepass_core::openpgp::*;
+use freepass_core::wireguard::*;
 use freepass_core::util::{guess_usage_stored, guess_usage_derived};
 use freepass_core::vault::{Vault, WritableVault};
 use freepass_core::encvault::*;
+use freepass_core::result::{Error, Result};
 use openfile::*;
+use clipboard;
 use util;
 
 macro_rules! interaction {
@@ -60,7 +69,7 @@ pub fn interact_entries(open_file: &mut OpenFile, debug: bool) {
     }
 }
 
-fn interact_entry(open_file: &mut OpenFile, entry_name: &str, entry: Entry, meta: EntryMetadata) {
+fn interact_entry(open_file: &mut OpenFile, entry_name: &str, mut entry: Entry, mut meta: EntryMetadata) {
     loop {
         interaction!({
             "Go back" => {
@@ -73,10 +82,18 @@ fn interact_entry(open_file: &mut OpenFile, entry_name: &str, entry: Entry, meta
                 return interact_entry_edit(open_file, entry_name, entry, meta);
             }
         }, entry.fields.keys(), |name: &str| {
-            let field = entry.fields.get(name).expect("Couldn't read selected field");
-            let output = process_output(entry_name, &open_file.master_key, field).unwrap();
+            let field = entry.fields.get(name).expect("Couldn't read selected field").clone();
+            let output = process_output(entry_name, &open_file.master_key, &field).unwrap();
             match output {
-                Output::PrivateText(s) => println!("{}", String::from_utf8(Vec::from(s.unsecure())).expect("Couldn't decode UTF-8")),
+                Output::PrivateText(s) => if clipboard::is_available() {
+                    interaction!({
+                        "Go back" => {},
+                        "Copy to clipboard" => { clipboard::copy(s.clone()) },
+                        "Print" => { println!("{}", String::from_utf8(Vec::from(s.unsecure())).expect("Couldn't decode UTF-8")) }
+                    })
+                } else {
+                    println!("{}", String::from_utf8(Vec::from(s.unsecure())).expect("Couldn't decode UTF-8"))
+                },
                 Output::OpenText(s) => println!("{}", s),
                 Output::PrivateBinary(s) => {
                     interaction!({
@@ -91,7 +108,33 @@ fn interact_entry(open_file: &mut OpenFile, entry_name: &str, entry: Entry, meta
                             "Go back" => {},
                             "Print public key" => { println!("{}", ssh_public_key_output(&output, entry_name).unwrap()) },
                             "Add private key to ssh-agent" => {
-                                ssh_agent_send_message(ssh_private_key_agent_message(&output, entry_name).unwrap()).unwrap()
+                                report_ssh_agent_result(ssh_agent_add_key(&output, entry_name, &SshKeyConstraints::default()), "Added")
+                            },
+                            "Add private key to ssh-agent with constraints" => {
+                                let constraints = SshKeyConstraints {
+                                    lifetime: util::read_text("Lifetime in seconds [forever]").and_then(|x| x.parse().ok()),
+                                    confirm: util::read_yesno("Confirm every use?"),
+                                };
+                                report_ssh_agent_result(ssh_agent_add_key(&output, entry_name, &constraints), "Added")
+                            },
+                            "Remove private key from ssh-agent" => {
+                                report_ssh_agent_result(ssh_agent_remove_key(&output), "Removed")
+                            },
+                            "Save private key file" => {
+                                if let Some(path) = util::read_text("File path") {
+                                    println!("Enter a passphrase for the file (empty for none):");
+                                    let passphrase = util::read_password();
+                                    println!("Enter the passphrase again:");
+                                    if passphrase != util::read_password() {
+                                        println!("The passphrases don't match");
+                                    } else {
+                                        let key_file = ssh_private_key_file_output(&output, entry_name, Some(&passphrase)).unwrap();
+                                        match util::create_private_file(&path) {
+                                            Ok(mut file) => file.write_all(key_file.unsecure()).expect("Couldn't write the private key file"),
+                                            Err(e) => println!("Couldn't create {}: {}", path, e),
+                                        }
+                                    }
+                                }
                             }
                         })
                     },
@@ -109,7 +152,120 @@ fn interact_entry(open_file: &mut OpenFile, entry_name: &str, entry: Entry, meta
                             }
                         })
                     },
-                    _ => panic!("Unsupported key usage"),
+                    Ed25519Usage::Minisign => {
+                        interaction!({
+                            "Go back" => {},
+                            "Print public key" => { print!("{}", minisign_public_key_output(&output).unwrap()) },
+                            "Sign a file" => {
+                                let path = pick_file(util::menu_cmd, env::current_dir().unwrap()).unwrap();
+                                let mut buffer = Vec::new();
+                                OpenOptions::new().read(true).open(&path).unwrap().read_to_end(&mut buffer).unwrap();
+                                let key_id = MinisignPublicKey::from_keypair(&output).unwrap().key_id;
+                                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                                let trusted_comment = minisign_default_trusted_comment(timestamp, path.file_name().unwrap().to_str().unwrap(), true);
+                                let signature = minisign_sign(&output, &format!("signature from freepass key {}", minisign_key_id_hex(&key_id)), &trusted_comment, &buffer[..], true).unwrap().into_bytes();
+                                let mut sigfile = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{}.minisig", path.to_str().unwrap())).unwrap();
+                                sigfile.write_all(&signature[..]).expect("Couldn't write the signature to the file");
+                            }
+                        })
+                    },
+                    Ed25519Usage::SQRL => {
+                        interaction!({
+                            "Go back" => {},
+                            "Log in with a sqrl:// URL" => {
+                                if let Some(text) = util::read_text("sqrl:// URL") {
+                                    match SqrlUrl::parse(&text) {
+                                        Ok(url) => {
+                                            let request = sqrl_client_request(&output, &url, "query").unwrap();
+                                            println!("POST {}\n\n{}", url.https_url, request.to_post_body());
+                                        },
+                                        Err(_) => println!("Not a valid sqrl:// URL: {}", text),
+                                    }
+                                }
+                            }
+                        })
+                    },
+                },
+                Output::X25519Keypair(_, _) => {
+                    interaction!({
+                        "Go back" => {},
+                        "Print recipient" => { println!("{}", age_recipient_output(&output).unwrap()) },
+                        "Print identity" => { println!("{}", String::from_utf8(Vec::from(age_identity_output(&output).unwrap().unsecure())).unwrap()) },
+                        "Encrypt a file" => {
+                            let path = pick_file(util::menu_cmd, env::current_dir().unwrap()).unwrap();
+                            let mut buffer = Vec::new();
+                            OpenOptions::new().read(true).open(&path).unwrap().read_to_end(&mut buffer).unwrap();
+                            let encrypted = age_encrypt(&[AgeRecipient::from_keypair(&output).unwrap()], &buffer[..]).unwrap();
+                            let mut outfile = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{}.age", path.to_str().unwrap())).unwrap();
+                            outfile.write_all(&encrypted[..]).expect("Couldn't write the encrypted file");
+                        },
+                        "Decrypt a file" => {
+                            let path = pick_file(util::menu_cmd, env::current_dir().unwrap()).unwrap();
+                            let mut buffer = Vec::new();
+                            OpenOptions::new().read(true).open(&path).unwrap().read_to_end(&mut buffer).unwrap();
+                            match age_decrypt(&output, &buffer[..]) {
+                                Ok(decrypted) => {
+                                    let path = path.to_str().unwrap();
+                                    let out_path = if path.ends_with(".age") { path[..path.len() - 4].to_owned() } else { format!("{}.decrypted", path) };
+                                    let mut outfile = util::create_private_file(&out_path).expect("Couldn't create the decrypted file");
+                                    outfile.write_all(decrypted.unsecure()).expect("Couldn't write the decrypted file");
+                                },
+                                Err(e) => println!("Couldn't decrypt the file: {:?}", e),
+                            }
+                        }
+                    })
+                },
+                Output::OpenPgpKey(_) => {
+                    interaction!({
+                        "Go back" => {},
+                        "Print fingerprint" => { println!("{}", openpgp_fingerprint_output(&output).unwrap()) },
+                        "Print public key" => {
+                            let user_id = util::read_text(&format!("User ID [{}]", entry_name)).unwrap_or(entry_name.to_owned());
+                            print!("{}", openpgp_public_key_output(&output, &user_id).unwrap())
+                        },
+                        "Print secret key" => {
+                            let user_id = util::read_text(&format!("User ID [{}]", entry_name)).unwrap_or(entry_name.to_owned());
+                            print!("{}", String::from_utf8(Vec::from(openpgp_secret_key_output(&output, &user_id).unwrap().unsecure())).unwrap())
+                        },
+                        "Sign a file" => {
+                            let path = pick_file(util::menu_cmd, env::current_dir().unwrap()).unwrap();
+                            let mut buffer = Vec::new();
+                            OpenOptions::new().read(true).open(&path).unwrap().read_to_end(&mut buffer).unwrap();
+                            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+                            let signature = openpgp_sign_detached(&output, &buffer[..], timestamp).unwrap().into_bytes();
+                            let mut sigfile = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{}.asc", path.to_str().unwrap())).unwrap();
+                            sigfile.write_all(&signature[..]).expect("Couldn't write the signature to the file");
+                        }
+                    })
+                },
+                Output::WireGuardKeypair(_, _) => {
+                    interaction!({
+                        "Go back" => {},
+                        "Print public key" => { println!("{}", wireguard_public_key_output(&output).unwrap()) },
+                        "Print [Interface] config" => {
+                            let address = util::read_text("Address (empty for none)");
+                            let listen_port = util::read_text("ListenPort (empty for none)").and_then(|p| p.parse::<u16>().ok());
+                            let config = wireguard_interface_config(&output, address.as_ref().map(|a| &a[..]), listen_port).unwrap();
+                            print!("{}", String::from_utf8(Vec::from(config.unsecure())).unwrap())
+                        }
+                    })
+                },
+                Output::Totp(code, remaining) => println!("{} (valid for {} more seconds)", code, remaining),
+                Output::Hotp(code, counter) => {
+                    println!("{}", code);
+                    if let Field::Stored { data, .. } = field {
+                        entry.fields.insert(name.to_owned(), Field::Stored { data: data, usage: StoredUsage::Hotp { counter: counter + 1 } });
+                        open_file.vault.put_entry(entry_name, &entry, &mut meta).unwrap();
+                        open_file.save();
+                    }
+                },
+                Output::TotpSecret(enrollment) => {
+                    interaction!({
+                        "Go back" => {},
+                        "Print current code" => { println!("{} (valid for {} more seconds)", enrollment.code, enrollment.remaining) },
+                        "Print secret" => { println!("{}", String::from_utf8(Vec::from(enrollment.secret.unsecure())).unwrap()) },
+                        "Print otpauth URI" => { println!("{}", String::from_utf8(Vec::from(enrollment.uri.unsecure())).unwrap()) }
+                    })
                 },
                 Output::Attachments(attachments) => {
                     interaction!({
@@ -125,6 +281,15 @@ fn interact_entry(open_file: &mut OpenFile, entry_name: &str, entry: Entry, meta
     }
 }
 
+fn report_ssh_agent_result(result: Result<()>, done: &str) {
+    match result {
+        Ok(()) => println!("{}!", done),
+        Err(Error::SSHAgentSocketNotFound) => println!("No ssh-agent running ($SSH_AUTH_SOCK is not set)"),
+        Err(Error::SSHAgentRefused) => println!("ssh-agent refused the request"),
+        Err(e) => println!("Couldn't talk to ssh-agent: {:?}", e),
+    }
+}
+
 fn interact_entry_edit(open_file: &mut OpenFile, entry_name: &str, mut entry: Entry, mut meta: EntryMetadata) {
     interaction!({
         &format!("  Save entry [{}]", entry_name) => {
@@ -146,8 +311,11 @@ fn interact_entry_edit(open_file: &mut OpenFile, entry_name: &str, mut entry: En
         },
         &format!("Rename entry [{}]", entry_name) => {
             let new_entry_name = util::read_text(&format!("New entry name [{}]", entry_name)).unwrap_or(entry_name.to_owned());
-            open_file.vault.remove_entry(entry_name);
-            open_file.vault.put_entry(&new_entry_name, &entry, &mut meta).unwrap();
+            if new_entry_name != entry_name {
+                open_file.vault.rename_entry(entry_name, &new_entry_name, &entry, &mut meta).unwrap();
+            } else {
+                open_file.vault.put_entry(entry_name, &entry, &mut meta).unwrap();
+            }
             return interact_entry_edit(open_file, &new_entry_name, entry, meta);
         },
         "Add field" => {
@@ -230,8 +398,16 @@ fn interact_field_edit(vault: &mut DecryptedVault, mut entry: Entry, field_name:
                         "Password(Pin)"       => { DerivedUsage::Password(PasswordTemplate::Pin) },
                         "Ed25519Key(SSH)"     => { DerivedUsage::Ed25519Key(Ed25519Usage::SSH) },
                         "Ed25519Key(Signify)" => { DerivedUsage::Ed25519Key(Ed25519Usage::Signify) },
+                        "Ed25519Key(Minisign)" => { DerivedUsage::Ed25519Key(Ed25519Usage::Minisign) },
                         "Ed25519Key(SQRL)"    => { DerivedUsage::Ed25519Key(Ed25519Usage::SQRL) },
-                        "RawKey"              => { DerivedUsage::RawKey }
+                        "RawKey"              => { DerivedUsage::RawKey },
+                        "X25519Key(age)"      => { DerivedUsage::X25519Key },
+                        "OpenPgp"             => { DerivedUsage::OpenPgp },
+                        "WireGuard"           => { DerivedUsage::WireGuard },
+                        "Bip39(12 words)"     => { DerivedUsage::Bip39(12) },
+                        "Bip39(18 words)"     => { DerivedUsage::Bip39(18) },
+                        "Bip39(24 words)"     => { DerivedUsage::Bip39(24) },
+                        "TotpSecret"          => { DerivedUsage::TotpSecret }
                     });
                     Field::Derived { counter: counter, site_name: site_name, usage: new_usage }
                 } else {
@@ -258,7 +434,10 @@ fn interact_field_edit(vault: &mut DecryptedVault, mut entry: Entry, field_name:
                     let new_usage = interaction!({
                         "Password"            => { StoredUsage::Password },
                         "Text"                => { StoredUsage::Text },
-                        "Attachments"         => { StoredUsage::Attachments }
+                        "Attachments"         => { StoredUsage::Attachments },
+                        "Totp"                => { StoredUsage::Totp },
+                        "Bip39"               => { StoredUsage::Bip39 },
+                        "Hotp"                => { StoredUsage::Hotp { counter: OtpParams::parse(data.unsecure()).map(|p| p.counter).unwrap_or(0) } }
                     });
                     Field::Stored { data: data, usage: new_usage }
                 } else {
@@ -16,14 +16,26 @@ mod util;
 mod openfile;
 mod interact;
 mod mergein;
+mod rekey;
+mod syncresolve;
+mod clipboard;
+mod script;
 
-use std::{env, fs, io};
-use clap::{Arg, App, SubCommand};
+use std::{env, fs, io, process};
+#[cfg(all(unix, not(target_os = "android"), not(target_os = "ios")))]
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(all(unix, not(target_os = "android"), not(target_os = "ios")))]
+use std::time::Duration;
+use clap::{Arg, ArgGroup, App, AppSettings, SubCommand};
 use openfile::*;
-use freepass_core::{import, vault::{self, Vault}, output};
+use freepass_core::{import, merge, vault::{self, Vault}, output, data::*, util::{guess_usage_stored, guess_usage_derived}};
+#[cfg(all(unix, not(target_os = "android"), not(target_os = "ios")))]
+use freepass_core::agent::{self, Agent, AgentIdentity};
 
 fn main() {
-    let matches = App::new("freepass")
+    clipboard::run_clearer_if_requested();
+    let app = App::new("freepass")
         .version(env!("CARGO_PKG_VERSION"))
         .author("Greg V <greg@unrelenting.technology>")
         .about("The free password manager for power users")
@@ -51,7 +63,7 @@ fn main() {
         .subcommand(
             SubCommand::with_name("mergein")
                 .about(
-                    "Adds entires from a second file (possibly importing from a foreign format) that don't exist in the first file (e.g. to resolve file sync conflicts)",
+                    "Merges a second file (possibly importing from a foreign format) into the first file (e.g. to resolve file sync conflicts)",
                 )
                 .arg(
                     Arg::with_name("IMPORTTYPE")
@@ -75,9 +87,311 @@ fn main() {
                         .long("secondname")
                         .takes_value(true)
                         .help("The user name to use for the second file, by default: $FREEPASS_SECOND_NAME or the first file name"),
+                )
+                .arg(
+                    Arg::with_name("DRYRUN")
+                        .long("dry-run")
+                        .help("Only print what would be merged, don't change the first file"),
+                )
+                .arg(prefer_arg())
+                .arg(
+                    Arg::with_name("JSON")
+                        .long("json")
+                        .help("Print the merge plan as JSON (with conflicts, nothing is merged unless --prefer is used)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Prints a field of an entry without interaction (for scripts)")
+                .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                .arg(Arg::with_name("FIELD").index(2).help("The field name, by default: password"))
+                .arg(
+                    Arg::with_name("BINARYFORMAT")
+                        .short("b")
+                        .long("binary")
+                        .takes_value(true)
+                        .possible_values(&["hex", "base64"])
+                        .default_value("hex")
+                        .help("How to print binary outputs (raw keys)"),
+                )
+                .arg(
+                    Arg::with_name("CLIP")
+                        .short("c")
+                        .long("clip")
+                        .help("Copy passwords and one-time codes to the clipboard instead of printing, see $FREEPASS_CLIPBOARD"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set")
+                .alias("add-field")
+                .about("Adds or replaces a field of an entry without interaction (stored values are read from stdin)")
+                .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name (created if it doesn't exist)"))
+                .arg(Arg::with_name("FIELD").required(true).index(2).help("The field name"))
+                .arg(Arg::with_name("STORED").long("stored").help("Store the value read from stdin"))
+                .arg(Arg::with_name("DERIVED").long("derived").help("Derive the value from the master password"))
+                .group(ArgGroup::with_name("KIND").args(&["STORED", "DERIVED"]).required(true))
+                .arg(
+                    Arg::with_name("USAGE")
+                        .long("usage")
+                        .takes_value(true)
+                        .possible_values(&["password", "text", "totp", "hotp", "bip39"])
+                        .requires("STORED")
+                        .help("The usage of a stored field, by default: guessed from the field name"),
+                )
+                .arg(
+                    Arg::with_name("COUNTER")
+                        .long("counter")
+                        .takes_value(true)
+                        .requires("DERIVED")
+                        .help("The counter of a derived field, by default: 1"),
+                )
+                .arg(
+                    Arg::with_name("SITE")
+                        .long("site")
+                        .takes_value(true)
+                        .requires("DERIVED")
+                        .help("The site name of a derived field, by default: the entry name"),
+                )
+                .arg(
+                    Arg::with_name("TEMPLATE")
+                        .long("template")
+                        .takes_value(true)
+                        .possible_values(&["maximum", "long", "medium", "short", "basic", "pin"])
+                        .requires("DERIVED")
+                        .help("The password template of a derived field, by default: guessed from the field name"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Removes an entry or a field of an entry without interaction")
+                .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                .arg(Arg::with_name("FIELD").index(2).help("The field name, by default: the whole entry is removed")),
+        )
+        .subcommand(
+            SubCommand::with_name("mv")
+                .about("Renames an entry without interaction")
+                .arg(Arg::with_name("OLDENTRY").required(true).index(1).help("The current entry name"))
+                .arg(Arg::with_name("NEWENTRY").required(true).index(2).help("The new entry name")),
+        )
+        .subcommand(
+            SubCommand::with_name("share")
+                .about("Encrypts an entry for another freepass user, to their derived SSH or signify public key")
+                .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                .arg(Arg::with_name("RECIPIENT").required(true).index(2).help("The recipient's public key file (ssh-ed25519 or signify)"))
+                .arg(Arg::with_name("OUTPUT").short("o").takes_value(true).help("Where to write the encrypted entry, by default: stdout")),
+        )
+        .subcommand(
+            SubCommand::with_name("receive")
+                .about("Adds an entry shared with one of your derived SSH or signify keys")
+                .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name of the key"))
+                .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the key field"))
+                .arg(Arg::with_name("INPUT").required(true).index(3).help("The file with the encrypted entry"))
+                .arg(Arg::with_name("NEWENTRY").short("n").takes_value(true).help("The name for the new entry, by default: the name it was shared with")),
+        )
+        .subcommand(
+            SubCommand::with_name("sync-resolve")
+                .about(
+                    "Merges the copies of the vault file made by sync tools on conflicts (Syncthing, Dropbox, Nextcloud) into it, then renames them to *.merged",
+                )
+                .arg(prefer_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("rekey")
+                .about(
+                    "Changes the master password (and optionally the user name) of the vault, re-encrypting all entries",
+                )
+                .arg(
+                    Arg::with_name("NEWNAME")
+                        .short("N")
+                        .long("newname")
+                        .takes_value(true)
+                        .help("The new user name, by default: the current one"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ssh-cert")
+                .about("Signs an SSH certificate for a public key, using a derived SSH key as the CA (like ssh-keygen -s)")
+                .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the SSH key field"))
+                .arg(
+                    Arg::with_name("PUBKEY")
+                        .short("f")
+                        .long("file")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The public key file to certify, e.g. id_ed25519.pub"),
+                )
+                .arg(
+                    Arg::with_name("IDENTITY")
+                        .short("I")
+                        .long("identity")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The key ID, logged by the server"),
+                )
+                .arg(
+                    Arg::with_name("PRINCIPALS")
+                        .short("n")
+                        .long("principals")
+                        .takes_value(true)
+                        .help("Comma-separated user (or host) names, by default: any"),
+                )
+                .arg(Arg::with_name("HOST").short("H").long("host").help("Makes a host certificate instead of a user certificate"))
+                .arg(
+                    Arg::with_name("VALIDITY")
+                        .short("V")
+                        .long("validity")
+                        .takes_value(true)
+                        .help("Seconds from now until the certificate expires, by default: never"),
+                )
+                .arg(Arg::with_name("SERIAL").short("z").long("serial").takes_value(true).help("The serial number, by default: 0"))
+                .arg(
+                    Arg::with_name("EXTENSION")
+                        .short("O")
+                        .long("extension")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("NAME or NAME=VALUE, replaces the default extensions (permit-pty etc.)"),
+                )
+                .arg(
+                    Arg::with_name("CRITICAL")
+                        .long("critical")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("NAME=VALUE critical option, e.g. force-command=/usr/bin/true"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("signify")
+                .about("Signs and verifies files with a derived signify key, like the signify tool")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("sign")
+                        .about("Signs a file (signify -S)")
+                        .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                        .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the signify key field"))
+                        .arg(Arg::with_name("MESSAGE").short("m").takes_value(true).required(true).help("The file to sign"))
+                        .arg(Arg::with_name("SIGNATURE").short("x").takes_value(true).help("The signature file, by default: MESSAGE.sig"))
+                        .arg(Arg::with_name("EMBED").short("e").help("Puts the message into the signature file")),
+                )
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("Verifies a signature (signify -V)")
+                        .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                        .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the signify key field"))
+                        .arg(
+                            Arg::with_name("MESSAGE")
+                                .short("m")
+                                .takes_value(true)
+                                .required(true)
+                                .help("The signed file (with -e: where to write the message)"),
+                        )
+                        .arg(Arg::with_name("SIGNATURE").short("x").takes_value(true).help("The signature file, by default: MESSAGE.sig"))
+                        .arg(Arg::with_name("EMBED").short("e").help("Extracts the message from the signature file")),
+                )
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Verifies a signed checksum list and the listed files (signify -C)")
+                        .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                        .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the signify key field"))
+                        .arg(
+                            Arg::with_name("SIGNATURE")
+                                .short("x")
+                                .takes_value(true)
+                                .required(true)
+                                .help("The signed checksum list, e.g. SHA256.sig"),
+                        )
+                        .arg(Arg::with_name("FILES").multiple(true).index(3).help("Only check these files, by default: all listed files")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("minisign")
+                .about("Signs and verifies files with a derived minisign key, like the minisign tool")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("sign")
+                        .about("Signs a file (minisign -S)")
+                        .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                        .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the minisign key field"))
+                        .arg(Arg::with_name("MESSAGE").short("m").takes_value(true).required(true).help("The file to sign"))
+                        .arg(Arg::with_name("SIGNATURE").short("x").takes_value(true).help("The signature file, by default: MESSAGE.minisig"))
+                        .arg(Arg::with_name("LEGACY").short("l").help("Signs the file itself instead of its BLAKE2b hash (for old minisign versions)"))
+                        .arg(Arg::with_name("TRUSTED_COMMENT").short("t").takes_value(true).help("The signed comment, by default: timestamp and file name"))
+                        .arg(Arg::with_name("UNTRUSTED_COMMENT").short("c").takes_value(true).help("The unsigned comment")),
+                )
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("Verifies a signature (minisign -V)")
+                        .arg(Arg::with_name("ENTRY").required_unless("PUBKEY").index(1).help("The entry name"))
+                        .arg(Arg::with_name("FIELD").required_unless("PUBKEY").index(2).help("The name of the minisign key field"))
+                        .arg(Arg::with_name("PUBKEY").short("p").takes_value(true).help("A minisign public key file to use instead of a field"))
+                        .arg(Arg::with_name("MESSAGE").short("m").takes_value(true).required(true).help("The signed file"))
+                        .arg(Arg::with_name("SIGNATURE").short("x").takes_value(true).help("The signature file, by default: MESSAGE.minisig")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("age")
+                .about("Encrypts and decrypts files with a derived age (X25519) key, like the age tool")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("encrypt")
+                        .about("Encrypts a file to the key (and other recipients)")
+                        .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                        .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the age key field"))
+                        .arg(Arg::with_name("INPUT").required(true).index(3).help("The file to encrypt"))
+                        .arg(Arg::with_name("OUTPUT").short("o").takes_value(true).help("The encrypted file, by default: INPUT.age"))
+                        .arg(
+                            Arg::with_name("RECIPIENT")
+                                .short("r")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("Another recipient (age1...) that can decrypt the file"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("decrypt")
+                        .about("Decrypts a file encrypted to the key")
+                        .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                        .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the age key field"))
+                        .arg(Arg::with_name("INPUT").required(true).index(3).help("The file to decrypt"))
+                        .arg(Arg::with_name("OUTPUT").short("o").takes_value(true).required(true).help("Where to write the decrypted file")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("openpgp")
+                .about("Exports a derived OpenPGP key and signs files with it")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Prints the ASCII-armored public key (or secret key, e.g. for gpg --import)")
+                        .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                        .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the OpenPGP key field"))
+                        .arg(Arg::with_name("USERID").short("u").takes_value(true).help("The user ID, e.g. 'Name <email>', by default: the entry name"))
+                        .arg(Arg::with_name("SECRET").short("s").long("secret").help("Exports the secret key")),
+                )
+                .subcommand(
+                    SubCommand::with_name("sign")
+                        .about("Makes a detached signature (gpg --armor --detach-sign)")
+                        .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                        .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the OpenPGP key field"))
+                        .arg(Arg::with_name("MESSAGE").required(true).index(3).help("The file to sign"))
+                        .arg(Arg::with_name("SIGNATURE").short("o").takes_value(true).help("The signature file, by default: MESSAGE.asc")),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("wireguard")
+                .about("Prints the [Interface] section of a wg-quick config with a derived WireGuard key")
+                .arg(Arg::with_name("ENTRY").required(true).index(1).help("The entry name"))
+                .arg(Arg::with_name("FIELD").required(true).index(2).help("The name of the WireGuard key field"))
+                .arg(Arg::with_name("ADDRESS").short("a").long("address").takes_value(true).help("The Address of the interface, e.g. 10.0.0.1/24"))
+                .arg(Arg::with_name("PORT").short("p").long("port").takes_value(true).help("The ListenPort of the interface")),
+        );
+    #[cfg(all(unix, not(target_os = "android"), not(target_os = "ios")))]
+    let app = app.subcommand(ssh_agent_subcommand());
+    let matches = app.get_matches();
 
     let file_path = unwrap_for_opt(opt_or_env(&matches, "FILE", "FREEPASS_FILE"), "file");
     let user_name = unwrap_for_opt(opt_or_env(&matches, "NAME", "FREEPASS_NAME"), "name");
@@ -87,7 +401,13 @@ fn main() {
 
     // Ensure we can write! Maybe someone somewhere would want to open the vault in read-only mode...
     // But the frustration of trying to save the vault while only having read permissions would be worse.
-    let mut open_file = OpenFile::open(file_path.clone(), &user_name, util::read_password(), true);
+    let mut open_file = match OpenFile::try_open(file_path.clone(), &user_name, util::read_password(), true) {
+        Ok(open_file) => open_file,
+        Err(e) => {
+            eprintln!("Couldn't read/decrypt freepass vault: {:?}", e);
+            process::exit(script::EXIT_DECRYPTION_FAILED);
+        },
+    };
 
     if debug {
         util::debug_output(&open_file.vault.data, "Vault");
@@ -116,13 +436,307 @@ fn main() {
                         Box::new(second_open_file.vault)
                     },
                 };
-                mergein::merge_in(&mut open_file.vault, &*second_vault);
-                open_file.save();
+                let prefer = conflict_preference(submatches);
+                let dry_run = submatches.is_present("DRYRUN");
+                let code = mergein::merge_in(&mut open_file.vault, &*second_vault, dry_run, prefer, submatches.is_present("JSON"));
+                // An incomplete merge still merged everything else
+                if !dry_run && (code == script::EXIT_OK || code == script::EXIT_MERGE_INCOMPLETE) {
+                    open_file.save();
+                }
+                process::exit(code);
             } else {
                 panic!("No options for mergein")
             }
         },
 
+        ("get", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let code = script::get(
+                    &mut open_file,
+                    submatches.value_of("ENTRY").unwrap(),
+                    submatches.value_of("FIELD").unwrap_or("password"),
+                    script::BinaryFormat::from_name(submatches.value_of("BINARYFORMAT").unwrap()),
+                    submatches.is_present("CLIP"),
+                );
+                process::exit(code);
+            } else {
+                panic!("No options for get")
+            }
+        },
+
+        ("set", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let field_name = submatches.value_of("FIELD").unwrap();
+                let field = if submatches.is_present("DERIVED") {
+                    Field::Derived {
+                        counter: submatches
+                            .value_of("COUNTER")
+                            .map(|c| c.parse::<u32>().expect("The counter must be a number"))
+                            .unwrap_or(1),
+                        site_name: submatches.value_of("SITE").map(|x| x.to_owned()),
+                        usage: submatches
+                            .value_of("TEMPLATE")
+                            .map(|t| DerivedUsage::Password(script::parse_template(t)))
+                            .unwrap_or_else(|| guess_usage_derived(field_name)),
+                    }
+                } else {
+                    let data = script::read_stdin_value();
+                    let usage = submatches
+                        .value_of("USAGE")
+                        .map(|u| script::parse_stored_usage(u, &data))
+                        .unwrap_or_else(|| guess_usage_stored(field_name));
+                    Field::Stored { data: data, usage: usage }
+                };
+                process::exit(script::set(&mut open_file, submatches.value_of("ENTRY").unwrap(), field_name, field));
+            } else {
+                panic!("No options for set")
+            }
+        },
+
+        ("rm", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                process::exit(script::remove(&mut open_file, submatches.value_of("ENTRY").unwrap(), submatches.value_of("FIELD")));
+            } else {
+                panic!("No options for rm")
+            }
+        },
+
+        ("mv", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                process::exit(script::rename(&mut open_file, submatches.value_of("OLDENTRY").unwrap(), submatches.value_of("NEWENTRY").unwrap()));
+            } else {
+                panic!("No options for mv")
+            }
+        },
+
+        ("share", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                process::exit(script::share(&open_file, submatches.value_of("ENTRY").unwrap(), submatches.value_of("RECIPIENT").unwrap(), submatches.value_of("OUTPUT")));
+            } else {
+                panic!("No options for share")
+            }
+        },
+
+        ("receive", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                process::exit(script::receive(
+                    &mut open_file,
+                    submatches.value_of("ENTRY").unwrap(),
+                    submatches.value_of("FIELD").unwrap(),
+                    submatches.value_of("INPUT").unwrap(),
+                    submatches.value_of("NEWENTRY"),
+                ));
+            } else {
+                panic!("No options for receive")
+            }
+        },
+
+        ("sync-resolve", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let code = syncresolve::sync_resolve(&mut open_file, conflict_preference(submatches));
+                process::exit(code);
+            } else {
+                panic!("No options for sync-resolve")
+            }
+        },
+
+        ("rekey", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let new_user_name = submatches.value_of("NEWNAME").map(|x| x.to_owned()).unwrap_or(user_name);
+                eprintln!("Enter the new master password:");
+                let new_password = util::read_password();
+                eprintln!("Enter the new master password again:");
+                if new_password != util::read_password() {
+                    panic!("The passwords don't match");
+                }
+                rekey::rekey(&mut open_file, &new_user_name, new_password);
+            } else {
+                panic!("No options for rekey")
+            }
+        },
+
+        ("ssh-cert", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let cert_type = if submatches.is_present("HOST") { output::SshCertificateType::Host } else { output::SshCertificateType::User };
+                let mut options = output::SshCertificateOptions::new(cert_type, submatches.value_of("IDENTITY").unwrap());
+                if let Some(principals) = submatches.value_of("PRINCIPALS") {
+                    options.principals = principals.split(',').map(|x| x.to_owned()).collect();
+                }
+                if let Some(validity) = submatches.value_of("VALIDITY") {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                    options.valid_after = now;
+                    options.valid_before = now + validity.parse::<u64>().expect("Validity must be a number of seconds");
+                }
+                if let Some(serial) = submatches.value_of("SERIAL") {
+                    options.serial = serial.parse().expect("Serial must be a number");
+                }
+                if let Some(extensions) = submatches.values_of("EXTENSION") {
+                    options.extensions = extensions.map(split_option).collect();
+                }
+                if let Some(critical_options) = submatches.values_of("CRITICAL") {
+                    options.critical_options = critical_options.map(split_option).collect();
+                }
+                process::exit(script::ssh_certificate(
+                    &open_file,
+                    submatches.value_of("ENTRY").unwrap(),
+                    submatches.value_of("FIELD").unwrap(),
+                    submatches.value_of("PUBKEY").unwrap(),
+                    &options,
+                ));
+            } else {
+                panic!("No options for ssh-cert")
+            }
+        },
+
+        ("signify", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let code = match submatches.subcommand() {
+                    ("sign", Some(args)) => {
+                        let message_path = args.value_of("MESSAGE").unwrap();
+                        let signature_path = args.value_of("SIGNATURE").map(|x| x.to_owned()).unwrap_or(format!("{}.sig", message_path));
+                        script::signify_sign_file(&open_file, args.value_of("ENTRY").unwrap(), args.value_of("FIELD").unwrap(), message_path, &signature_path, args.is_present("EMBED"))
+                    },
+                    (command, Some(args)) => match script::signify_public_key(&open_file, args.value_of("ENTRY").unwrap(), args.value_of("FIELD").unwrap()) {
+                        Ok(ref pubkey) if command == "verify" => {
+                            let message_path = args.value_of("MESSAGE").unwrap();
+                            let signature_path = args.value_of("SIGNATURE").map(|x| x.to_owned()).unwrap_or(format!("{}.sig", message_path));
+                            script::signify_verify_file(pubkey, message_path, &signature_path, args.is_present("EMBED"))
+                        },
+                        Ok(ref pubkey) => {
+                            let files = args.values_of("FILES").map(|x| x.collect::<Vec<_>>()).unwrap_or(Vec::new());
+                            script::signify_check_files(pubkey, args.value_of("SIGNATURE").unwrap(), &files)
+                        },
+                        Err(code) => code,
+                    },
+                    _ => panic!("No options for signify"),
+                };
+                process::exit(code);
+            } else {
+                panic!("No options for signify")
+            }
+        },
+
+        ("minisign", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let code = match submatches.subcommand() {
+                    ("sign", Some(args)) => {
+                        let message_path = args.value_of("MESSAGE").unwrap();
+                        let signature_path = args.value_of("SIGNATURE").map(|x| x.to_owned()).unwrap_or(format!("{}.minisig", message_path));
+                        script::minisign_sign_file(
+                            &open_file,
+                            args.value_of("ENTRY").unwrap(),
+                            args.value_of("FIELD").unwrap(),
+                            message_path,
+                            &signature_path,
+                            args.value_of("UNTRUSTED_COMMENT"),
+                            args.value_of("TRUSTED_COMMENT"),
+                            !args.is_present("LEGACY"),
+                        )
+                    },
+                    ("verify", Some(args)) => {
+                        let message_path = args.value_of("MESSAGE").unwrap();
+                        let signature_path = args.value_of("SIGNATURE").map(|x| x.to_owned()).unwrap_or(format!("{}.minisig", message_path));
+                        script::minisign_verify_file(
+                            &open_file,
+                            args.value_of("ENTRY").unwrap_or(""),
+                            args.value_of("FIELD").unwrap_or(""),
+                            args.value_of("PUBKEY"),
+                            message_path,
+                            &signature_path,
+                        )
+                    },
+                    _ => panic!("No options for minisign"),
+                };
+                process::exit(code);
+            } else {
+                panic!("No options for minisign")
+            }
+        },
+
+        ("age", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let code = match submatches.subcommand() {
+                    ("encrypt", Some(args)) => {
+                        let input_path = args.value_of("INPUT").unwrap();
+                        let output_path = args.value_of("OUTPUT").map(|x| x.to_owned()).unwrap_or(format!("{}.age", input_path));
+                        let recipients = args.values_of("RECIPIENT").map(|x| x.collect::<Vec<_>>()).unwrap_or(Vec::new());
+                        script::age_encrypt_file(&open_file, args.value_of("ENTRY").unwrap(), args.value_of("FIELD").unwrap(), &recipients, input_path, &output_path)
+                    },
+                    ("decrypt", Some(args)) => {
+                        script::age_decrypt_file(&open_file, args.value_of("ENTRY").unwrap(), args.value_of("FIELD").unwrap(), args.value_of("INPUT").unwrap(), args.value_of("OUTPUT").unwrap())
+                    },
+                    _ => panic!("No options for age"),
+                };
+                process::exit(code);
+            } else {
+                panic!("No options for age")
+            }
+        },
+
+        ("openpgp", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let code = match submatches.subcommand() {
+                    ("export", Some(args)) => {
+                        let entry_name = args.value_of("ENTRY").unwrap();
+                        let user_id = args.value_of("USERID").unwrap_or(entry_name);
+                        script::openpgp_export(&open_file, entry_name, args.value_of("FIELD").unwrap(), user_id, args.is_present("SECRET"))
+                    },
+                    ("sign", Some(args)) => {
+                        let message_path = args.value_of("MESSAGE").unwrap();
+                        let signature_path = args.value_of("SIGNATURE").map(|x| x.to_owned()).unwrap_or(format!("{}.asc", message_path));
+                        script::openpgp_sign_file(&open_file, args.value_of("ENTRY").unwrap(), args.value_of("FIELD").unwrap(), message_path, &signature_path)
+                    },
+                    _ => panic!("No options for openpgp"),
+                };
+                process::exit(code);
+            } else {
+                panic!("No options for openpgp")
+            }
+        },
+
+        ("wireguard", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let listen_port = submatches.value_of("PORT").map(|p| p.parse::<u16>().expect("Invalid port"));
+                process::exit(script::wireguard_config(
+                    &open_file,
+                    submatches.value_of("ENTRY").unwrap(),
+                    submatches.value_of("FIELD").unwrap(),
+                    submatches.value_of("ADDRESS"),
+                    listen_port,
+                ));
+            } else {
+                panic!("No options for wireguard")
+            }
+        },
+
+        #[cfg(all(unix, not(target_os = "android"), not(target_os = "ios")))]
+        ("ssh-agent", submatches_opt) => {
+            if let Some(submatches) = submatches_opt {
+                let socket_path = submatches.value_of("SOCKET").map(PathBuf::from).unwrap_or_else(|| {
+                    agent::private_socket_dir().expect("Couldn't create the socket directory").join("agent.sock")
+                });
+                let lifetime = submatches.value_of("LIFETIME").map(|x| Duration::from_secs(x.parse().expect("Lifetime must be a number of seconds")));
+                let confirm = submatches.is_present("CONFIRM");
+                let mut agent = Agent::new(open_file.master_key.clone(), Box::new(|name: &str| util::read_yesno(&format!("Sign with the SSH key of {}?", name))));
+                for entry_name in submatches.values_of("ENTRY").unwrap() {
+                    let (entry, _meta) = open_file.vault.get_entry(entry_name).expect("Couldn't read entry");
+                    for (field_name, field) in entry.fields.iter() {
+                        if let Field::Derived { usage: DerivedUsage::Ed25519Key(Ed25519Usage::SSH), .. } = *field {
+                            agent.add_identity(AgentIdentity::new(entry_name, field.clone(), confirm, lifetime)).expect("Couldn't derive SSH key");
+                            eprintln!("Added {} / {}", entry_name, field_name);
+                        }
+                    }
+                }
+                if agent.len() == 0 {
+                    panic!("No SSH keys in these entries");
+                }
+                println!("SSH_AUTH_SOCK={}; export SSH_AUTH_SOCK;", socket_path.display());
+                agent.serve(&socket_path).expect("Couldn't run the agent");
+            } else {
+                panic!("No options for ssh-agent")
+            }
+        },
+
         ("export", _) => {
             let stdout = io::stdout();
             let mut stdout = stdout.lock();
@@ -138,6 +752,11 @@ fn main() {
                     Some(output::Output::PrivateText(s)) => String::from_utf8(Vec::from(s.unsecure())).expect("Couldn't decode UTF-8"),
                     _ => "".to_string(),
                 };
+                // Bitwarden wants the secret (or otpauth URI) here, not the current code
+                let totp = entry.fields.values().filter_map(|f| match *f {
+                    Field::Stored { ref data, usage: StoredUsage::Totp } => Some(String::from_utf8(Vec::from(data.unsecure())).expect("Couldn't decode UTF-8")),
+                    _ => None,
+                }).next().unwrap_or("".to_string());
                 let extras = entry.fields.iter().filter(|(k, _)| *k != "username" && *k != "login" && *k != "password").filter_map(|(k, v)| {
                     match output::process_output(k, &open_file.master_key, v) {
                         Ok(output::Output::OpenText(s)) => Some(format!("{}: {}", k, s)),
@@ -145,12 +764,14 @@ fn main() {
                         _ => None,
                     }
                 }).collect::<Vec<_>>().join("\n");
-                writer.write_record(&[file_path.to_string(), "".to_string(), "login".to_string(), name.to_string(), "".to_string(), extras, format!("https://{}", name), username, password, "".to_string()]).unwrap();
+                writer.write_record(&[file_path.to_string(), "".to_string(), "login".to_string(), name.to_string(), "".to_string(), extras, format!("https://{}", name), username, password, totp]).unwrap();
             }
             writer.flush().unwrap();
         },
 
-        ("interact", _) | _ => interact::interact_entries(&mut open_file, debug),
+        ("interact", _) | _ => {
+            interact::interact_entries(&mut open_file, debug);
+        },
 
     }
 }
@@ -168,3 +789,48 @@ fn unwrap_for_opt(opt: Option<String>, name: &str) -> String {
         None => panic!("Option {} not found", name),
     }
 }
+
+fn prefer_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("PREFER")
+        .long("prefer")
+        .takes_value(true)
+        .possible_values(&["newer", "ours", "theirs"])
+        .help("Resolve conflicting field changes without asking: keep the newer value, the first file's (ours) or the second file's (theirs)")
+}
+
+fn conflict_preference(matches: &clap::ArgMatches) -> Option<merge::ConflictPreference> {
+    matches.value_of("PREFER").map(|p| match p {
+        "newer" => merge::ConflictPreference::Newer,
+        "ours" => merge::ConflictPreference::Ours,
+        "theirs" => merge::ConflictPreference::Theirs,
+        _ => unreachable!(),
+    })
+}
+
+#[cfg(all(unix, not(target_os = "android"), not(target_os = "ios")))]
+fn ssh_agent_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("ssh-agent")
+        .about("Serves the derived SSH keys of entries as an ssh-agent, until interrupted")
+        .arg(
+            Arg::with_name("SOCKET")
+                .short("s")
+                .long("socket")
+                .takes_value(true)
+                .help("The socket path, by default: agent.sock in a new private directory in the temp directory"),
+        )
+        .arg(Arg::with_name("CONFIRM").short("c").long("confirm").help("Asks for confirmation before every signature"))
+        .arg(
+            Arg::with_name("LIFETIME")
+                .short("t")
+                .long("lifetime")
+                .takes_value(true)
+                .help("Forgets the keys after this many seconds, by default: never"),
+        )
+        .arg(Arg::with_name("ENTRY").required(true).multiple(true).index(1).help("The entries with SSH keys to serve"))
+}
+
+/// Splits NAME=VALUE, a missing value is empty.
+fn split_option(option: &str) -> (String, String) {
+    let mut parts = option.splitn(2, '=');
+    (parts.next().unwrap_or("").to_owned(), parts.next().unwrap_or("").to_owned())
+}
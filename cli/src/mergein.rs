@@ -1,34 +1,254 @@
+use freepass_core::data::*;
 use freepass_core::merge::*;
 use freepass_core::vault::{Vault, WritableVault};
 use util;
+use script;
 
-pub fn merge_in<I: ?Sized, F: ?Sized>(into_vault: &mut I, from_vault: &F)
+/// Shows what a conflicting field is without printing passwords and keys.
+fn describe_field(field: &Option<Field>, version: &FieldVersion) -> String {
+    let description = match *field {
+        None => "removed".to_owned(),
+        Some(Field::Derived { counter, ref usage, .. }) => format!("derived {:?}, counter {}", usage, counter),
+        Some(Field::Stored { ref data, usage: StoredUsage::Text }) =>
+            format!("text '{}'", String::from_utf8(data.unsecure().to_vec()).unwrap_or("<invalid UTF-8>".to_owned())),
+        Some(Field::Stored { ref usage, .. }) => format!("stored {:?}", usage),
+    };
+    format!("{} (changed {})", description, version.updated_at.to_rfc2822())
+}
+
+fn action_name(action: MergeAction) -> &'static str {
+    match action {
+        MergeAction::Add => "add",
+        MergeAction::Update => "update",
+        MergeAction::Keep => "keep",
+        MergeAction::Remove => "remove",
+        MergeAction::NotRestore => "not-restore",
+        MergeAction::NameCollision => "name-collision",
+        MergeAction::Error => "error",
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            // All of U+0000 to U+001F must be escaped, the others (DEL, C1) just to keep the output readable
+            c if c.is_control() => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn json_opt_string(s: Option<String>) -> String {
+    s.map(|s| json_string(&s)).unwrap_or("null".to_owned())
+}
+
+/// The plan's entries as a JSON array, without any field values.
+fn plan_json(plan: &MergePlan, prefer: Option<ConflictPreference>) -> String {
+    let entries = plan.entries.iter().map(|e| {
+        let conflicts = e.conflicts.iter().map(|c| format!(
+            "{{\"field\":{},\"ours_updated_at\":{},\"theirs_updated_at\":{},\"ours_removed\":{},\"theirs_removed\":{},\"resolution\":{}}}",
+            json_string(&c.field_name),
+            json_string(&c.ours_version.updated_at.to_rfc3339()),
+            json_string(&c.theirs_version.updated_at.to_rfc3339()),
+            c.ours.is_none(),
+            c.theirs.is_none(),
+            match prefer.map(|p| p.takes_theirs(c)) {
+                Some(true) => "\"theirs\"",
+                Some(false) => "\"ours\"",
+                None => "null",
+            }
+        )).collect::<Vec<_>>();
+        format!(
+            "{{\"name\":{},\"action\":\"{}\",\"renamed_from\":{},\"ours_updated_at\":{},\"theirs_updated_at\":{},\"conflicts\":[{}]}}",
+            json_string(&e.entry_name),
+            action_name(e.action),
+            json_opt_string(e.renamed_from.clone()),
+            json_opt_string(e.ours_updated_at.map(|d| d.to_rfc3339())),
+            json_opt_string(e.theirs_updated_at.map(|d| d.to_rfc3339())),
+            conflicts.join(",")
+        )
+    }).collect::<Vec<_>>();
+    format!("[{}]", entries.join(","))
+}
+
+/// What was done, as a JSON array. Conflicts in `failed_conflicts` couldn't be resolved.
+fn log_json(log: &[MergeLogEntry], failed_conflicts: &[&MergeConflict]) -> String {
+    let entries = log.iter().map(|lentry| {
+        let (name, result) = match *lentry {
+            MergeLogEntry::Added(ref entry_name) => (entry_name, "added"),
+            MergeLogEntry::Updated(ref entry_name) => (entry_name, "updated"),
+            MergeLogEntry::Unchanged(ref entry_name) => (entry_name, "unchanged"),
+            MergeLogEntry::Renamed(ref old_name, ref new_name) =>
+                return format!("{{\"name\":{},\"result\":\"renamed\",\"renamed_from\":{}}}", json_string(new_name), json_string(old_name)),
+            MergeLogEntry::NameCollision(ref entry_name) => (entry_name, "name-collision"),
+            MergeLogEntry::Removed(ref entry_name) => (entry_name, "removed"),
+            MergeLogEntry::NotRestored(ref entry_name) => (entry_name, "not-restored"),
+            MergeLogEntry::Conflict(ref c) =>
+                return format!("{{\"name\":{},\"result\":\"conflict\",\"field\":{},\"resolved\":{}}}",
+                               json_string(&c.entry_name), json_string(&c.field_name), !failed_conflicts.contains(&c)),
+            MergeLogEntry::WeirdError(ref entry_name) => (entry_name, "error"),
+        };
+        format!("{{\"name\":{},\"result\":\"{}\"}}", json_string(name), result)
+    }).collect::<Vec<_>>();
+    format!("[{}]", entries.join(","))
+}
+
+fn print_plan(plan: &MergePlan, prefer: Option<ConflictPreference>) {
+    for e in &plan.entries {
+        let ours = e.ours_updated_at.map(|d| d.to_rfc2822()).unwrap_or("-".to_owned());
+        let theirs = e.theirs_updated_at.map(|d| d.to_rfc2822()).unwrap_or("-".to_owned());
+        match e.action {
+            MergeAction::Add => println!("Would add: {} (changed {})", e.entry_name, theirs),
+            MergeAction::Update => println!("Would update: {} (changed {} here, {} in the second file)", e.entry_name, ours, theirs),
+            MergeAction::Keep => (),
+            MergeAction::Remove => println!("Would remove: {} (changed {}, deleted in the second file {})", e.entry_name, ours, theirs),
+            MergeAction::NotRestore => println!("Would not restore: {} (changed in the second file {}, deleted here {})", e.entry_name, theirs, ours),
+            MergeAction::NameCollision => println!("ERROR! A different entry with the same name exists, rename one of them: {}", e.entry_name),
+            MergeAction::Error => println!("ERROR! Couldn't read: {}", e.entry_name),
+        }
+        if let Some(ref old_name) = e.renamed_from {
+            println!("Would rename: {} -> {}", old_name, e.entry_name);
+        }
+        for c in &e.conflicts {
+            println!("Conflict in field '{}' of entry '{}':", c.field_name, c.entry_name);
+            println!("  this file:   {}", describe_field(&c.ours, &c.ours_version));
+            println!("  second file: {}", describe_field(&c.theirs, &c.theirs_version));
+            match prefer.map(|p| p.takes_theirs(c)) {
+                Some(true) => println!("  would use the second file's value"),
+                Some(false) => println!("  would keep this file's value"),
+                None => println!("  would ask"),
+            }
+        }
+    }
+}
+
+/// With `dry_run`, only prints the plan. With `json`, the plan (and the log, after merging) is printed as JSON,
+/// and nothing is merged if there are conflicts but no `prefer`ence (there's no one to ask).
+/// Returns the exit code: `EXIT_MERGE_INCOMPLETE` if some entries or conflicts couldn't be merged.
+pub fn merge_in<I: ?Sized, F: ?Sized>(into_vault: &mut I, from_vault: &F, dry_run: bool, prefer: Option<ConflictPreference>, json: bool) -> i32
     where I: Vault + WritableVault,
           F: Vault
 {
-    let log = merge_vaults(into_vault, from_vault);
-    for lentry in &log {
-        match *lentry {
-            MergeLogEntry::Added(ref entry_name) => println!("Added: {}", entry_name),
-            MergeLogEntry::IsNewer(_) => (),
-            MergeLogEntry::IsOlder(ref entry_name) => println!("Not updated in the second file: {}", entry_name),
-            MergeLogEntry::WeirdError(ref entry_name) => println!("ERROR! Couldn't add: {}", entry_name),
+    let plan = plan_merge(&*into_vault, from_vault);
+    let plan_entries = if json { plan_json(&plan, prefer) } else { String::new() };
+    if dry_run {
+        if json {
+            println!("{{\"entries\":{}}}", plan_entries);
+        } else {
+            print_plan(&plan, prefer);
         }
+        return script::EXIT_OK;
     }
-    // Handling all IsNewers together for better output
+    if json && prefer.is_none() && plan.entries.iter().any(|e| !e.conflicts.is_empty()) {
+        println!("{{\"entries\":{}}}", plan_entries);
+        eprintln!("Not merging: there are conflicts, use --prefer to resolve them");
+        return script::EXIT_MERGE_CONFLICTS;
+    }
+    let log = apply_merge(into_vault, plan);
+    if !json {
+        for lentry in &log {
+            match *lentry {
+                MergeLogEntry::Added(ref entry_name) => println!("Added: {}", entry_name),
+                MergeLogEntry::Updated(ref entry_name) => println!("Updated: {}", entry_name),
+                MergeLogEntry::Renamed(ref old_name, ref new_name) => println!("Renamed: {} -> {}", old_name, new_name),
+                MergeLogEntry::NameCollision(ref entry_name) => println!("ERROR! A different entry with the same name exists, rename one of them: {}", entry_name),
+                MergeLogEntry::Removed(ref entry_name) => println!("Removed (deleted in the second file): {}", entry_name),
+                MergeLogEntry::NotRestored(ref entry_name) => println!("Not restored (deleted in this file): {}", entry_name),
+                MergeLogEntry::Unchanged(_) | MergeLogEntry::Conflict(_) => (),
+                MergeLogEntry::WeirdError(ref entry_name) => println!("ERROR! Couldn't add: {}", entry_name),
+            }
+        }
+    }
+    // Handling all conflicts together for better output
+    let mut failed_conflicts = Vec::new();
     for lentry in &log {
-        if let MergeLogEntry::IsNewer(ref entry_name) = *lentry {
-            if util::read_yesno(&format!("Update entry '{}'?", entry_name)) {
-                if let Ok((from_entry, from_entry_meta)) = from_vault.get_entry(&entry_name) {
-                    if let Ok(_) = into_vault.put_entry(&entry_name, &from_entry, &mut from_entry_meta.clone()) {
-                        println!("Added: {}", entry_name)
-                    } else {
-                        println!("ERROR! Couldn't add: {}", entry_name)
-                    }
-                } else {
-                    println!("ERROR! Couldn't add: {}", entry_name)
-                }
+        if let MergeLogEntry::Conflict(ref conflict) = *lentry {
+            let take_theirs = match prefer {
+                Some(p) => p.takes_theirs(conflict),
+                None => {
+                    println!("Conflict in field '{}' of entry '{}':", conflict.field_name, conflict.entry_name);
+                    println!("  this file:   {}", describe_field(&conflict.ours, &conflict.ours_version));
+                    println!("  second file: {}", describe_field(&conflict.theirs, &conflict.theirs_version));
+                    util::read_yesno("Use the value from the second file?")
+                },
+            };
+            if let Err(_) = resolve_conflict(into_vault, conflict, take_theirs) {
+                eprintln!("ERROR! Couldn't update: {}", conflict.entry_name);
+                failed_conflicts.push(conflict);
             }
         }
     }
+    if json {
+        println!("{{\"entries\":{},\"log\":{}}}", plan_entries, log_json(&log, &failed_conflicts));
+    }
+    let incomplete = log.iter().any(|lentry| match *lentry {
+        MergeLogEntry::NameCollision(_) | MergeLogEntry::WeirdError(_) => true,
+        _ => false,
+    });
+    if incomplete || !failed_conflicts.is_empty() {
+        script::EXIT_MERGE_INCOMPLETE
+    } else {
+        script::EXIT_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secstr::SecStr;
+    use freepass_core::encvault::DecryptedVault;
+
+    fn new_vault(password: Option<&str>) -> DecryptedVault {
+        let mut vault = DecryptedVault::new(SecStr::new(vec![1; 64]), SecStr::new(vec![2; 16]));
+        if let Some(password) = password {
+            let mut entry = Entry::default();
+            entry.fields.insert("password".to_owned(), Field::Stored { data: SecStr::from(password), usage: StoredUsage::Password });
+            vault.put_entry("example.com", &entry, &mut EntryMetadata::default()).unwrap();
+        }
+        vault
+    }
+
+    #[test]
+    fn test_merge_in_incomplete() {
+        // Different entries with the same name
+        let mut ours = new_vault(Some("one"));
+        let theirs = new_vault(Some("two"));
+        assert_eq!(merge_in(&mut ours, &theirs, true, None, true), script::EXIT_OK);
+        assert_eq!(merge_in(&mut ours, &theirs, false, None, true), script::EXIT_MERGE_INCOMPLETE);
+        assert_eq!(merge_in(&mut ours, &theirs, false, Some(ConflictPreference::Theirs), false), script::EXIT_MERGE_INCOMPLETE);
+        assert_eq!(merge_in(&mut ours, &new_vault(None), false, None, true), script::EXIT_OK);
+    }
+
+    #[test]
+    fn test_log_json() {
+        let log = vec![
+            MergeLogEntry::Added("a".to_owned()),
+            MergeLogEntry::Renamed("b".to_owned(), "c".to_owned()),
+            MergeLogEntry::NameCollision("d".to_owned()),
+        ];
+        assert_eq!(log_json(&log, &[]), concat!(
+            "[{\"name\":\"a\",\"result\":\"added\"},",
+            "{\"name\":\"c\",\"result\":\"renamed\",\"renamed_from\":\"b\"},",
+            "{\"name\":\"d\",\"result\":\"name-collision\"}]"
+        ));
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a \"quoted\" \\ path\n"), "\"a \\\"quoted\\\" \\\\ path\\n\"");
+        for c in (0..0x20).chain(0x7f..0xa0).filter_map(::std::char::from_u32) {
+            let escaped = json_string(&c.to_string());
+            assert!(!escaped.chars().any(|e| e.is_control()), "{:?} is not escaped", c);
+        }
+        assert_eq!(json_string("\u{1}\t\u{7f}ö"), "\"\\u0001\\t\\u007fö\"");
+    }
 }
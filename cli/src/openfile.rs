@@ -1,7 +1,16 @@
-use std::{fs, io};
+use std::{env, fs, io};
+use std::time::Duration;
 use secstr::SecStr;
 use rusterpassword::gen_master_key;
 use freepass_core::encvault::*;
+use freepass_core::result::Result;
+
+/// How many days removed entries are remembered for merging.
+fn tombstone_days() -> Option<u64> {
+    env::var("FREEPASS_TOMBSTONE_DAYS")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+}
 
 pub struct OpenFile {
     pub vault: DecryptedVault,
@@ -11,6 +20,11 @@ pub struct OpenFile {
 
 impl OpenFile {
     pub fn open(file_path: String, user_name: &str, password: SecStr, need_write: bool) -> OpenFile {
+        OpenFile::try_open(file_path, user_name, password, need_write).expect("Couldn't read/decrypt freepass vault")
+    }
+
+    /// Like `open`, but returns vault decryption errors instead of panicking.
+    pub fn try_open(file_path: String, user_name: &str, password: SecStr, need_write: bool) -> Result<OpenFile> {
         let file = match fs::OpenOptions::new()
                   .read(true)
                   .write(need_write)
@@ -20,14 +34,18 @@ impl OpenFile {
             Err(ref err) => panic!("Could not open file {}: {}", &file_path, err),
         };
         let master_key = gen_master_key(password, user_name).unwrap();
-        OpenFile {
-            vault: match file {
-                Some(f) => DecryptedVault::open(gen_entries_key(&master_key), gen_outer_key(&master_key), f).expect("Couldn't read/decrypt freepass vault"),
-                None => DecryptedVault::new(gen_entries_key(&master_key), gen_outer_key(&master_key)),
-            },
+        let mut vault = match file {
+            Some(f) => DecryptedVault::open(gen_entries_key(&master_key), gen_outer_key(&master_key), f)?,
+            None => DecryptedVault::new(gen_entries_key(&master_key), gen_outer_key(&master_key)),
+        };
+        if let Some(days) = tombstone_days() {
+            vault.tombstone_lifetime = Duration::from_secs(days * 24 * 60 * 60);
+        }
+        Ok(OpenFile {
+            vault: vault,
             master_key: master_key,
             file_path: file_path,
-        }
+        })
     }
 
     pub fn save(self: &mut OpenFile) {
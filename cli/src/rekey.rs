@@ -0,0 +1,46 @@
+use secstr::SecStr;
+use rusterpassword::gen_master_key;
+use freepass_core::data::*;
+use freepass_core::output::{is_snapshottable, snapshot_derived_field};
+use freepass_core::vault::{Vault, WritableVault};
+use freepass_core::encvault::*;
+use openfile::*;
+use util;
+
+pub fn rekey(open_file: &mut OpenFile, new_user_name: &str, new_password: SecStr) {
+    let new_master_key = gen_master_key(new_password, new_user_name).unwrap();
+    let entry_names: Vec<String> = open_file.vault.entry_names().cloned().collect();
+    for entry_name in entry_names {
+        let (mut entry, mut meta) = open_file.vault.get_entry(&entry_name).expect("Couldn't read entry");
+        let mut changed = false;
+        for (field_name, field) in entry.fields.iter_mut() {
+            let usage = match *field {
+                Field::Derived { usage, .. } => usage,
+                Field::Stored { .. } => continue,
+            };
+            println!("Derived field '{}' of entry '{}' ({:?}) will produce a different output with the new master password!", field_name, entry_name, usage);
+            if is_snapshottable(&usage) {
+                if util::read_yesno(&format!("Convert '{}' of '{}' to a stored field with the current value?", field_name, entry_name)) {
+                    let snapshot = snapshot_derived_field(&entry_name, &open_file.master_key, field).expect("Couldn't generate the current value");
+                    *field = snapshot;
+                    changed = true;
+                }
+            } else {
+                println!("(Keys can't be converted to stored fields, make sure you don't need the old one anymore.)");
+            }
+        }
+        if changed {
+            open_file.vault.put_entry(&entry_name, &entry, &mut meta).unwrap();
+        }
+    }
+    if !util::read_yesno("Change the master password now?") {
+        return ();
+    }
+    open_file
+        .vault
+        .rekey(gen_entries_key(&new_master_key), gen_outer_key(&new_master_key))
+        .expect("Couldn't re-encrypt the vault");
+    open_file.master_key = new_master_key;
+    open_file.save();
+    println!("Done! Use the new master password from now on.");
+}
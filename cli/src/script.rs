@@ -0,0 +1,621 @@
+use std::{io, str};
+use std::fs::File;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::prelude::*;
+use base64;
+use hex;
+use secstr::SecStr;
+use freepass_core::output::*;
+use freepass_core::data::*;
+use freepass_core::minisign::*;
+use freepass_core::age::*;
+use freepass_core::share::*;
+use freepass_core::openpgp::*;
+use freepass_core::wireguard::*;
+use freepass_core::result::{Error, Result as FreepassResult};
+use freepass_core::bip39::normalize_mnemonic;
+use freepass_core::otp::OtpParams;
+use freepass_core::vault::{Vault, WritableVault};
+use openfile::*;
+use clipboard;
+
+// Exit codes for the non-interactive subcommands
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ENTRY_NOT_FOUND: i32 = 2;
+pub const EXIT_FIELD_NOT_FOUND: i32 = 3;
+pub const EXIT_DECRYPTION_FAILED: i32 = 4;
+pub const EXIT_UNSUPPORTED_OUTPUT: i32 = 5;
+pub const EXIT_ENTRY_EXISTS: i32 = 6;
+pub const EXIT_INVALID_VALUE: i32 = 7;
+pub const EXIT_VERIFICATION_FAILED: i32 = 8;
+pub const EXIT_MERGE_CONFLICTS: i32 = 9;
+pub const EXIT_MERGE_INCOMPLETE: i32 = 10;
+
+pub enum BinaryFormat {
+    Hex,
+    Base64,
+}
+
+impl BinaryFormat {
+    pub fn from_name(name: &str) -> BinaryFormat {
+        match name {
+            "base64" => BinaryFormat::Base64,
+            _ => BinaryFormat::Hex,
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> String {
+        match *self {
+            BinaryFormat::Hex => hex::encode(data),
+            BinaryFormat::Base64 => base64::encode(data),
+        }
+    }
+}
+
+/// Finds a field and processes its output, or prints the problem and returns the exit code.
+fn lookup_field(open_file: &OpenFile, entry_name: &str, field_name: &str) -> Result<(Entry, EntryMetadata, Field, Output), i32> {
+    let (entry, meta) = get_entry_for_update(open_file, entry_name)?;
+    let field = match entry.fields.get(field_name) {
+        Some(field) => field.clone(),
+        None => {
+            eprintln!("Field not found: {}", field_name);
+            return Err(EXIT_FIELD_NOT_FOUND);
+        },
+    };
+    let output = match process_output(entry_name, &open_file.master_key, &field) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Couldn't process field {}: {:?}", field_name, e);
+            return Err(EXIT_DECRYPTION_FAILED);
+        },
+    };
+    Ok((entry, meta, field, output))
+}
+
+/// Prints the output of a field to stdout (or copies private text to the clipboard), returns the exit code.
+pub fn get(open_file: &mut OpenFile, entry_name: &str, field_name: &str, binary_format: BinaryFormat, clip: bool) -> i32 {
+    let (mut entry, mut meta, field, output) = match lookup_field(open_file, entry_name, field_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    match output {
+        Output::PrivateText(ref s) if clip => clipboard::copy(s.clone()),
+        Output::PrivateText(ref s) => println!("{}", String::from_utf8(Vec::from(s.unsecure())).expect("Couldn't decode UTF-8")),
+        Output::OpenText(ref s) => println!("{}", s),
+        Output::PrivateBinary(ref s) => println!("{}", binary_format.encode(s.unsecure())),
+        Output::Ed25519Keypair(Ed25519Usage::SSH, _, _) => println!("{}", ssh_public_key_output(&output, entry_name).unwrap()),
+        Output::Ed25519Keypair(Ed25519Usage::Signify, _, _) => print!("{}", signify_public_key_output(&output, entry_name).unwrap()),
+        Output::Ed25519Keypair(Ed25519Usage::Minisign, _, _) => print!("{}", minisign_public_key_output(&output).unwrap()),
+        Output::X25519Keypair(_, _) => println!("{}", age_recipient_output(&output).unwrap()),
+        Output::WireGuardKeypair(_, _) => println!("{}", wireguard_public_key_output(&output).unwrap()),
+        Output::OpenPgpKey(_) => print!("{}", openpgp_public_key_output(&output, entry_name).unwrap()),
+        Output::Totp(ref code, _) => print_code(code, clip),
+        Output::TotpSecret(ref enrollment) => print_code(&enrollment.code, clip),
+        Output::Hotp(ref code, counter) => {
+            print_code(code, clip);
+            if let Field::Stored { data, .. } = field {
+                entry.fields.insert(field_name.to_owned(), Field::Stored { data: data, usage: StoredUsage::Hotp { counter: counter + 1 } });
+                open_file.vault.put_entry(entry_name, &entry, &mut meta).unwrap();
+                open_file.save();
+            }
+        },
+        _ => {
+            eprintln!("Field {} can't be printed", field_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+    }
+    EXIT_OK
+}
+
+/// One-time codes are copied to the clipboard with `clip` too.
+fn print_code(code: &str, clip: bool) {
+    if clip {
+        clipboard::copy(SecStr::from(code));
+    } else {
+        println!("{}", code);
+    }
+}
+
+/// Prints a certificate for the public key in the file, signed by the SSH key field, returns the exit code.
+pub fn ssh_certificate(open_file: &OpenFile, entry_name: &str, field_name: &str, public_key_path: &str, options: &SshCertificateOptions) -> i32 {
+    let (_, _, _, output) = match lookup_field(open_file, entry_name, field_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let mut public_key = String::new();
+    File::open(public_key_path)
+        .and_then(|mut f| f.read_to_string(&mut public_key))
+        .expect("Couldn't read the public key file");
+    match ssh_certificate_output(&output, &public_key, options) {
+        Ok(cert) => println!("{}", cert),
+        Err(Error::InappropriateFormat) => {
+            eprintln!("Field {} is not an SSH key", field_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+        Err(Error::InvalidSshPublicKey) => {
+            eprintln!("Not an SSH public key: {}", public_key_path);
+            return EXIT_INVALID_VALUE;
+        },
+        Err(e) => panic!("Couldn't make the certificate: {:?}", e),
+    }
+    EXIT_OK
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .expect(&format!("Couldn't read {}", path));
+    data
+}
+
+fn write_file(path: &str, data: &[u8]) {
+    File::create(path)
+        .and_then(|mut f| f.write_all(data))
+        .expect(&format!("Couldn't write {}", path));
+}
+
+/// Signs the message file with the signify key field, like `signify -S`. Returns the exit code.
+pub fn signify_sign_file(open_file: &OpenFile, entry_name: &str, field_name: &str, message_path: &str, signature_path: &str, embed: bool) -> i32 {
+    let (_, _, _, output) = match lookup_field(open_file, entry_name, field_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let message = read_file(message_path);
+    let comment = format!("signed with freepass key: {}", entry_name);
+    let signature = if embed {
+        signify_sign_embedded(&output, &comment, &message)
+    } else {
+        signify_sign(&output, &comment, &message).map(|s| s.into_bytes())
+    };
+    match signature {
+        Ok(signature) => write_file(signature_path, &signature),
+        Err(_) => {
+            eprintln!("Field {} is not a signify key", field_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+    }
+    EXIT_OK
+}
+
+/// The public key of the signify key field.
+pub fn signify_public_key(open_file: &OpenFile, entry_name: &str, field_name: &str) -> Result<SignifyPublicKey, i32> {
+    let (_, _, _, output) = lookup_field(open_file, entry_name, field_name)?;
+    SignifyPublicKey::from_keypair(&output).map_err(|_| {
+        eprintln!("Field {} is not a signify key", field_name);
+        EXIT_UNSUPPORTED_OUTPUT
+    })
+}
+
+fn verification_result(result: FreepassResult<()>) -> i32 {
+    match result {
+        Ok(()) => {
+            println!("Signature Verified");
+            EXIT_OK
+        },
+        Err(Error::SignifyWrongKey) => {
+            eprintln!("Verification failed: checked against wrong key");
+            EXIT_VERIFICATION_FAILED
+        },
+        Err(Error::InvalidSignifyFormat) => {
+            eprintln!("Not a signify signature");
+            EXIT_INVALID_VALUE
+        },
+        Err(_) => {
+            eprintln!("Signature verification failed");
+            EXIT_VERIFICATION_FAILED
+        },
+    }
+}
+
+/// Verifies a signature, like `signify -V`. With `embed`, the message is extracted from the signature file
+/// and written to `message_path`. Returns the exit code.
+pub fn signify_verify_file(pubkey: &SignifyPublicKey, message_path: &str, signature_path: &str, embed: bool) -> i32 {
+    let signature = read_file(signature_path);
+    if embed {
+        let result = signify_verify_embedded(pubkey, &signature);
+        if let Ok(ref message) = result {
+            write_file(message_path, message);
+        }
+        verification_result(result.map(|_| ()))
+    } else {
+        verification_result(signify_verify(pubkey, &signature, &read_file(message_path)))
+    }
+}
+
+/// Signs the message file with the minisign key field, like `minisign -S`. Returns the exit code.
+pub fn minisign_sign_file(open_file: &OpenFile, entry_name: &str, field_name: &str, message_path: &str, signature_path: &str,
+                          untrusted_comment: Option<&str>, trusted_comment: Option<&str>, prehashed: bool) -> i32 {
+    let (_, _, _, output) = match lookup_field(open_file, entry_name, field_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let key_id = match MinisignPublicKey::from_keypair(&output) {
+        Ok(pubkey) => pubkey.key_id,
+        Err(_) => {
+            eprintln!("Field {} is not a minisign key", field_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+    };
+    let untrusted_comment = untrusted_comment.map(|c| c.to_owned())
+        .unwrap_or(format!("signature from freepass key {}", minisign_key_id_hex(&key_id)));
+    let trusted_comment = trusted_comment.map(|c| c.to_owned()).unwrap_or_else(|| {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let file_name = Path::new(message_path).file_name().and_then(|n| n.to_str()).unwrap_or(message_path);
+        minisign_default_trusted_comment(timestamp, file_name, prehashed)
+    });
+    match minisign_sign(&output, &untrusted_comment, &trusted_comment, &read_file(message_path), prehashed) {
+        Ok(signature) => write_file(signature_path, signature.as_bytes()),
+        Err(_) => {
+            eprintln!("Comments can't contain line breaks");
+            return EXIT_INVALID_VALUE;
+        },
+    }
+    EXIT_OK
+}
+
+/// Verifies a minisign signature with the public key of the minisign key field (or a given public key file),
+/// like `minisign -V`. Returns the exit code.
+pub fn minisign_verify_file(open_file: &OpenFile, entry_name: &str, field_name: &str, pubkey_path: Option<&str>,
+                            message_path: &str, signature_path: &str) -> i32 {
+    let pubkey = if let Some(pubkey_path) = pubkey_path {
+        match MinisignPublicKey::parse(&String::from_utf8_lossy(&read_file(pubkey_path))) {
+            Ok(pubkey) => pubkey,
+            Err(_) => {
+                eprintln!("Not a minisign public key: {}", pubkey_path);
+                return EXIT_INVALID_VALUE;
+            },
+        }
+    } else {
+        let (_, _, _, output) = match lookup_field(open_file, entry_name, field_name) {
+            Ok(x) => x,
+            Err(code) => return code,
+        };
+        match MinisignPublicKey::from_keypair(&output) {
+            Ok(pubkey) => pubkey,
+            Err(_) => {
+                eprintln!("Field {} is not a minisign key", field_name);
+                return EXIT_UNSUPPORTED_OUTPUT;
+            },
+        }
+    };
+    let signature = String::from_utf8_lossy(&read_file(signature_path)).into_owned();
+    match minisign_verify(&pubkey, &signature, &read_file(message_path)) {
+        Ok(trusted_comment) => {
+            println!("Signature and comment signature verified");
+            println!("Trusted comment: {}", trusted_comment);
+            EXIT_OK
+        },
+        Err(Error::MinisignWrongKey) => {
+            eprintln!("Signature key id in {} is not the key id of the public key", signature_path);
+            EXIT_VERIFICATION_FAILED
+        },
+        Err(Error::InvalidMinisignFormat) => {
+            eprintln!("Not a minisign signature");
+            EXIT_INVALID_VALUE
+        },
+        Err(_) => {
+            eprintln!("Signature verification failed");
+            EXIT_VERIFICATION_FAILED
+        },
+    }
+}
+
+/// Encrypts the input file to the age key field and the other recipients, like `age -r`. Returns the exit code.
+pub fn age_encrypt_file(open_file: &OpenFile, entry_name: &str, field_name: &str, recipients: &[&str], input_path: &str, output_path: &str) -> i32 {
+    let (_, _, _, output) = match lookup_field(open_file, entry_name, field_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let mut all_recipients = match AgeRecipient::from_keypair(&output) {
+        Ok(recipient) => vec![recipient],
+        Err(_) => {
+            eprintln!("Field {} is not an age key", field_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+    };
+    for recipient in recipients {
+        match AgeRecipient::parse(recipient) {
+            Ok(recipient) => all_recipients.push(recipient),
+            Err(_) => {
+                eprintln!("Not an age recipient: {}", recipient);
+                return EXIT_INVALID_VALUE;
+            },
+        }
+    }
+    write_file(output_path, &age_encrypt(&all_recipients, &read_file(input_path)).expect("Couldn't encrypt"));
+    EXIT_OK
+}
+
+/// Decrypts the input file with the age key field, like `age -d`. Returns the exit code.
+pub fn age_decrypt_file(open_file: &OpenFile, entry_name: &str, field_name: &str, input_path: &str, output_path: &str) -> i32 {
+    let (_, _, _, output) = match lookup_field(open_file, entry_name, field_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    match age_decrypt(&output, &read_file(input_path)) {
+        Ok(plaintext) => write_file(output_path, plaintext.unsecure()),
+        Err(Error::InappropriateFormat) => {
+            eprintln!("Field {} is not an age key", field_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+        Err(Error::InvalidAgeFormat) => {
+            eprintln!("Not an age file");
+            return EXIT_INVALID_VALUE;
+        },
+        Err(Error::AgeNoMatchingIdentity) => {
+            eprintln!("The file was not encrypted to field {}", field_name);
+            return EXIT_DECRYPTION_FAILED;
+        },
+        Err(_) => {
+            eprintln!("Decryption failed: the file is damaged");
+            return EXIT_DECRYPTION_FAILED;
+        },
+    }
+    EXIT_OK
+}
+
+/// Verifies a signed checksum list, then the listed files (or only the given ones), like `signify -C`.
+/// Returns the exit code.
+pub fn signify_check_files(pubkey: &SignifyPublicKey, signature_path: &str, file_names: &[&str]) -> i32 {
+    let checksums = match signify_verify_checksums(pubkey, &read_file(signature_path)) {
+        Ok(checksums) => checksums,
+        Err(Error::InvalidSignifyFormat) => {
+            eprintln!("Not a signed checksum list");
+            return EXIT_INVALID_VALUE;
+        },
+        Err(e) => return verification_result(Err(e)),
+    };
+    let mut code = EXIT_OK;
+    for checksum in checksums.iter().filter(|c| file_names.len() == 0 || file_names.contains(&&c.file_name[..])) {
+        match File::open(&checksum.file_name).map_err(Error::from).and_then(|mut f| checksum.matches(&mut f)) {
+            Ok(true) => println!("{}: OK", checksum.file_name),
+            Ok(false) => {
+                println!("{}: FAIL", checksum.file_name);
+                code = EXIT_VERIFICATION_FAILED;
+            },
+            Err(_) => {
+                println!("{}: MISSING", checksum.file_name);
+                code = EXIT_VERIFICATION_FAILED;
+            },
+        }
+    }
+    for file_name in file_names.iter().filter(|n| !checksums.iter().any(|c| c.file_name == **n)) {
+        println!("{}: NOT LISTED", file_name);
+        code = EXIT_VERIFICATION_FAILED;
+    }
+    code
+}
+
+/// The HOTP counter is taken from the `otpauth://hotp/...?counter=` URI in `data`, if there's one.
+pub fn parse_stored_usage(name: &str, data: &SecStr) -> StoredUsage {
+    match name {
+        "password" => StoredUsage::Password,
+        "text" => StoredUsage::Text,
+        "totp" => StoredUsage::Totp,
+        "hotp" => StoredUsage::Hotp { counter: OtpParams::parse(data.unsecure()).map(|p| p.counter).unwrap_or(0) },
+        "bip39" => StoredUsage::Bip39,
+        x => panic!("Unsupported stored usage {}", x),
+    }
+}
+
+pub fn parse_template(name: &str) -> PasswordTemplate {
+    match name {
+        "maximum" => PasswordTemplate::Maximum,
+        "long" => PasswordTemplate::Long,
+        "medium" => PasswordTemplate::Medium,
+        "short" => PasswordTemplate::Short,
+        "basic" => PasswordTemplate::Basic,
+        "pin" => PasswordTemplate::Pin,
+        x => panic!("Unsupported password template {}", x),
+    }
+}
+
+/// Reads a stored field value from stdin, without the trailing newline.
+pub fn read_stdin_value() -> SecStr {
+    let mut buffer = Vec::new();
+    io::stdin().read_to_end(&mut buffer).expect("Couldn't read the value from stdin");
+    if buffer.ends_with(b"\n") {
+        buffer.pop();
+        if buffer.ends_with(b"\r") {
+            buffer.pop();
+        }
+    }
+    SecStr::new(buffer)
+}
+
+fn get_entry_for_update(open_file: &OpenFile, entry_name: &str) -> Result<(Entry, EntryMetadata), i32> {
+    match open_file.vault.get_entry(entry_name) {
+        Ok(x) => Ok(x),
+        Err(Error::EntryNotFound) => {
+            eprintln!("Entry not found: {}", entry_name);
+            Err(EXIT_ENTRY_NOT_FOUND)
+        },
+        Err(e) => {
+            eprintln!("Couldn't decrypt entry {}: {:?}", entry_name, e);
+            Err(EXIT_DECRYPTION_FAILED)
+        },
+    }
+}
+
+/// Adds or replaces a field, creating the entry if it doesn't exist. Returns the exit code.
+pub fn set(open_file: &mut OpenFile, entry_name: &str, field_name: &str, field: Field) -> i32 {
+    let field = match field {
+        Field::Stored { data, usage: StoredUsage::Bip39 } => {
+            match str::from_utf8(data.unsecure()).ok().and_then(|m| normalize_mnemonic(m).ok()) {
+                Some(mnemonic) => Field::Stored { data: mnemonic, usage: StoredUsage::Bip39 },
+                None => {
+                    eprintln!("Not a valid BIP39 mnemonic (unknown word or wrong checksum)");
+                    return EXIT_INVALID_VALUE;
+                },
+            }
+        },
+        field => field,
+    };
+    let (mut entry, mut meta) = if open_file.vault.entry_names().any(|n| n == entry_name) {
+        match get_entry_for_update(open_file, entry_name) {
+            Ok(x) => x,
+            Err(code) => return code,
+        }
+    } else {
+        (Entry::default(), EntryMetadata::default())
+    };
+    entry.fields.insert(field_name.to_owned(), field);
+    open_file.vault.put_entry(entry_name, &entry, &mut meta).unwrap();
+    open_file.save();
+    EXIT_OK
+}
+
+/// Removes a field, or the whole entry if `field_name` is `None`. Returns the exit code.
+pub fn remove(open_file: &mut OpenFile, entry_name: &str, field_name: Option<&str>) -> i32 {
+    let (mut entry, mut meta) = match get_entry_for_update(open_file, entry_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    if let Some(field_name) = field_name {
+        if entry.fields.remove(field_name).is_none() {
+            eprintln!("Field not found: {}", field_name);
+            return EXIT_FIELD_NOT_FOUND;
+        }
+        open_file.vault.put_entry(entry_name, &entry, &mut meta).unwrap();
+    } else {
+        open_file.vault.remove_entry(entry_name);
+    }
+    open_file.save();
+    EXIT_OK
+}
+
+/// Renames an entry. Returns the exit code.
+pub fn rename(open_file: &mut OpenFile, old_entry_name: &str, new_entry_name: &str) -> i32 {
+    let (entry, mut meta) = match get_entry_for_update(open_file, old_entry_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    if open_file.vault.entry_names().any(|n| n == new_entry_name) {
+        eprintln!("Entry already exists: {}", new_entry_name);
+        return EXIT_ENTRY_EXISTS;
+    }
+    open_file.vault.rename_entry(old_entry_name, new_entry_name, &entry, &mut meta).unwrap();
+    open_file.save();
+    EXIT_OK
+}
+
+/// Prints the public (or secret) key of the OpenPGP key field with the user ID. Returns the exit code.
+pub fn openpgp_export(open_file: &OpenFile, entry_name: &str, field_name: &str, user_id: &str, secret: bool) -> i32 {
+    let (_, _, _, output) = match lookup_field(open_file, entry_name, field_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let key = if secret {
+        openpgp_secret_key_output(&output, user_id).map(|k| String::from_utf8(Vec::from(k.unsecure())).unwrap())
+    } else {
+        openpgp_public_key_output(&output, user_id)
+    };
+    match key {
+        Ok(key) => print!("{}", key),
+        Err(_) => {
+            eprintln!("Field {} is not an OpenPGP key", field_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+    }
+    EXIT_OK
+}
+
+/// Makes an armored detached signature of the file with the OpenPGP key field, like `gpg -ab`. Returns the exit code.
+pub fn openpgp_sign_file(open_file: &OpenFile, entry_name: &str, field_name: &str, message_path: &str, signature_path: &str) -> i32 {
+    let (_, _, _, output) = match lookup_field(open_file, entry_name, field_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+    match openpgp_sign_detached(&output, &read_file(message_path), timestamp) {
+        Ok(signature) => write_file(signature_path, signature.as_bytes()),
+        Err(_) => {
+            eprintln!("Field {} is not an OpenPGP key", field_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+    }
+    EXIT_OK
+}
+
+/// Prints the `[Interface]` section of a `wg-quick` config with the WireGuard key field. Returns the exit code.
+pub fn wireguard_config(open_file: &OpenFile, entry_name: &str, field_name: &str, address: Option<&str>, listen_port: Option<u16>) -> i32 {
+    let (_, _, _, output) = match lookup_field(open_file, entry_name, field_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    match wireguard_interface_config(&output, address, listen_port) {
+        Ok(config) => print!("{}", String::from_utf8(Vec::from(config.unsecure())).unwrap()),
+        Err(_) => {
+            eprintln!("Field {} is not a WireGuard key", field_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+    }
+    EXIT_OK
+}
+
+/// Encrypts an entry to someone else's SSH or signify public key. The result is printed as Base64
+/// (or written to `output_path`). Returns the exit code.
+pub fn share(open_file: &OpenFile, entry_name: &str, recipient_path: &str, output_path: Option<&str>) -> i32 {
+    let (entry, _) = match get_entry_for_update(open_file, entry_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let recipient = match parse_share_recipient(&String::from_utf8_lossy(&read_file(recipient_path))) {
+        Ok(recipient) => recipient,
+        Err(_) => {
+            eprintln!("Not an ssh-ed25519 or signify public key: {}", recipient_path);
+            return EXIT_INVALID_VALUE;
+        },
+    };
+    let entry = match snapshot_entry(entry_name, &open_file.master_key, &entry) {
+        Ok(entry) => entry,
+        Err(_) => {
+            eprintln!("Entry {} has derived keys, they can't be shared", entry_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+    };
+    let shared = base64::encode(&share_entry(&recipient, entry_name, &entry).expect("Couldn't encrypt the entry"));
+    match output_path {
+        Some(path) => write_file(path, format!("{}\n", shared).as_bytes()),
+        None => println!("{}", shared),
+    }
+    EXIT_OK
+}
+
+/// Decrypts an entry shared with the SSH or signify key field and adds it to the vault,
+/// under its original name or `new_entry_name`. Returns the exit code.
+pub fn receive(open_file: &mut OpenFile, key_entry_name: &str, key_field_name: &str, input_path: &str, new_entry_name: Option<&str>) -> i32 {
+    let (_, _, _, output) = match lookup_field(open_file, key_entry_name, key_field_name) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let data = match base64::decode(String::from_utf8_lossy(&read_file(input_path)).trim()) {
+        Ok(data) => data,
+        Err(_) => {
+            eprintln!("Not a shared entry: {}", input_path);
+            return EXIT_INVALID_VALUE;
+        },
+    };
+    let (entry_name, entry) = match receive_entry(&output, &data) {
+        Ok(x) => x,
+        Err(Error::InappropriateFormat) => {
+            eprintln!("Field {} is not an SSH or signify key", key_field_name);
+            return EXIT_UNSUPPORTED_OUTPUT;
+        },
+        Err(_) => {
+            eprintln!("Couldn't decrypt the shared entry with field {}", key_field_name);
+            return EXIT_DECRYPTION_FAILED;
+        },
+    };
+    let entry_name = new_entry_name.unwrap_or(&entry_name);
+    if open_file.vault.entry_names().any(|n| n == entry_name) {
+        eprintln!("Entry already exists: {}", entry_name);
+        return EXIT_ENTRY_EXISTS;
+    }
+    open_file.vault.put_entry(entry_name, &entry, &mut EntryMetadata::default()).unwrap();
+    open_file.save();
+    eprintln!("Received entry {}", entry_name);
+    EXIT_OK
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::Path;
+use freepass_core::encvault::*;
+use freepass_core::merge::ConflictPreference;
+use freepass_core::result::Error;
+use openfile::*;
+use mergein;
+use script;
+
+/// Whether `name` is a copy of the vault file `vault_name` made by a sync tool on a conflict:
+/// Syncthing (`vault.sync-conflict-20170603-120000-ABCDEFG.fpass`),
+/// Dropbox and Nextcloud (`vault (conflicted copy).fpass`, `vault (Chloe's conflicted copy 2017-06-03).fpass`).
+fn is_conflict_copy(vault_name: &str, name: &str) -> bool {
+    let (stem, ext) = match vault_name.rfind('.') {
+        Some(i) if i > 0 => (&vault_name[..i], &vault_name[i..]),
+        _ => (vault_name, ""),
+    };
+    if name.len() <= stem.len() + ext.len() || !name.starts_with(stem) || !name.ends_with(ext) {
+        return false;
+    }
+    let middle = &name[stem.len()..name.len() - ext.len()];
+    if middle.starts_with(".sync-conflict-") {
+        is_syncthing_suffix(&middle[".sync-conflict-".len()..])
+    } else if middle.starts_with(" (") && middle.ends_with(')') {
+        // Only one pair of parentheses, "vault (old) (conflicted copy)" is a copy of "vault (old)"
+        let note = &middle[2..middle.len() - 1];
+        note.contains("conflicted copy") && !note.contains(|c| c == '(' || c == ')')
+    } else {
+        false
+    }
+}
+
+/// `20170603-120000-ABCDEFG`: the date, the time and the ID of the device.
+fn is_syncthing_suffix(suffix: &str) -> bool {
+    let parts = suffix.split('-').collect::<Vec<_>>();
+    parts.len() == 3
+        && parts[0].len() == 8 && parts[0].chars().all(|c| c.is_ascii_digit())
+        && parts[1].len() == 6 && parts[1].chars().all(|c| c.is_ascii_digit())
+        && parts[2].len() > 0 && parts[2].chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Merges all conflict copies of the vault file (opened with the same key) into it,
+/// then renames the completely merged ones to `<name>.merged`. Returns the exit code.
+pub fn sync_resolve(open_file: &mut OpenFile, prefer: Option<ConflictPreference>) -> i32 {
+    let file_path = open_file.file_path.clone();
+    let path = Path::new(&file_path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let vault_name = path.file_name().and_then(|n| n.to_str()).expect("Weird vault file name");
+    let mut copies = fs::read_dir(dir)
+        .expect("Couldn't read the vault directory")
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.file_name().and_then(|n| n.to_str()).map(|n| is_conflict_copy(vault_name, n)).unwrap_or(false))
+        .collect::<Vec<_>>();
+    copies.sort();
+    if copies.is_empty() {
+        println!("No conflict copies of {} found", vault_name);
+        return script::EXIT_OK;
+    }
+    let mut code = script::EXIT_OK;
+    let mut merged_any = false;
+    let mut merged = Vec::new();
+    for copy_path in copies {
+        let copy_vault = fs::File::open(&copy_path)
+            .map_err(Error::from)
+            .and_then(|f| DecryptedVault::open(gen_entries_key(&open_file.master_key), gen_outer_key(&open_file.master_key), f));
+        match copy_vault {
+            Ok(copy_vault) => {
+                println!("Merging {}", copy_path.display());
+                merged_any = true;
+                match mergein::merge_in(&mut open_file.vault, &copy_vault, false, prefer, false) {
+                    script::EXIT_OK => merged.push(copy_path),
+                    merge_code => {
+                        eprintln!("Not everything was merged, keeping {}", copy_path.display());
+                        code = merge_code;
+                    },
+                }
+            },
+            Err(e) => {
+                eprintln!("Couldn't read/decrypt {}: {:?}", copy_path.display(), e);
+                code = script::EXIT_DECRYPTION_FAILED;
+            },
+        }
+    }
+    if !merged_any {
+        return code;
+    }
+    open_file.save();
+    for copy_path in merged {
+        let mut aside_path = copy_path.clone().into_os_string();
+        aside_path.push(".merged");
+        match fs::rename(&copy_path, &aside_path) {
+            Ok(()) => println!("Moved aside: {}", Path::new(&aside_path).display()),
+            Err(e) => {
+                // Merging it again next time doesn't change anything
+                eprintln!("Couldn't move {} aside: {}", copy_path.display(), e);
+                code = script::EXIT_MERGE_INCOMPLETE;
+            },
+        }
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_conflict_copy() {
+        // Syncthing
+        assert!(is_conflict_copy("vault.fpass", "vault.sync-conflict-20170603-120000-ABCDEFG.fpass"));
+        // Dropbox
+        assert!(is_conflict_copy("vault.fpass", "vault (conflicted copy).fpass"));
+        assert!(is_conflict_copy("vault.fpass", "vault (Clarke Griffin's conflicted copy 2017-06-03).fpass"));
+        // Nextcloud / ownCloud
+        assert!(is_conflict_copy("vault.fpass", "vault (conflicted copy 2017-06-03 120000).fpass"));
+        assert!(is_conflict_copy("vault", "vault (conflicted copy 2017-06-03 120000)"));
+
+        // The vault itself
+        assert!(!is_conflict_copy("vault.fpass", "vault.fpass"));
+        // Similarly named files
+        assert!(!is_conflict_copy("vault.fpass", "vault2.fpass"));
+        assert!(!is_conflict_copy("vault.fpass", "vault (copy).fpass"));
+        assert!(!is_conflict_copy("vault.fpass", "vault.sync-conflict-notes.fpass"));
+        assert!(!is_conflict_copy("vault.fpass", "vault.sync-conflict-20170603-120000-ABCDEFG.fpass.merged"));
+        assert!(!is_conflict_copy("vault.fpass", "vault (conflicted copy).kdbx"));
+        // Conflict copies of other vaults
+        assert!(!is_conflict_copy("vault.fpass", "work.sync-conflict-20170603-120000-ABCDEFG.fpass"));
+        assert!(!is_conflict_copy("vault.fpass", "vault.old.sync-conflict-20170603-120000-ABCDEFG.fpass"));
+        assert!(!is_conflict_copy("vault.fpass", "vault (old) (conflicted copy).fpass"));
+        assert!(!is_conflict_copy("vault (old).fpass", "vault (conflicted copy).fpass"));
+    }
+}
@@ -1,5 +1,6 @@
 use std::{env, io, fs};
 use std::io::prelude::*;
+use std::path::Path;
 use std::process::{Command, Stdio};
 use serde_cbor;
 use serde::Serialize;
@@ -115,3 +116,20 @@ pub fn debug_output<T: Serialize>(data: &T, description: &str) {
     println!("--- CBOR debug output (http://cbor.me to decode) of {} ---\n{}\n", description,
         hex::encode(serde_cbor::to_vec(data).unwrap()));
 }
+
+/// Creates a new file (failing if it already exists) that only the current user can read, e.g. for private keys.
+pub fn create_private_file<P: AsRef<Path>>(path: P) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    set_private_mode(&mut options);
+    options.open(path)
+}
+
+#[cfg(unix)]
+fn set_private_mode(options: &mut fs::OpenOptions) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+}
+
+#[cfg(not(unix))]
+fn set_private_mode(_options: &mut fs::OpenOptions) {}
@@ -0,0 +1,524 @@
+//! [age](https://age-encryption.org/v1) file encryption with derived X25519 identities.
+
+use std::str;
+use base64;
+use secstr::SecStr;
+use sodiumoxide::crypto::scalarmult::curve25519;
+use sodiumoxide::crypto::aead::chacha20poly1305_ietf as aead;
+use crypto::hkdf::{hkdf_extract, hkdf_expand};
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rand::RngCore;
+use rand::os::OsRng;
+use output::Output;
+use result::{Error, Result};
+use util::{blake2b, hmac};
+
+const AGE_VERSION_LINE: &'static str = "age-encryption.org/v1";
+const AGE_X25519_INFO: &'static [u8] = b"age-encryption.org/v1/X25519";
+const AGE_IDENTITY_HRP: &'static str = "age-secret-key-";
+const AGE_RECIPIENT_HRP: &'static str = "age";
+const FILE_KEY_BYTES: usize = 16;
+const HEADER_MAC_BYTES: usize = 32;
+const PAYLOAD_NONCE_BYTES: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+const COLUMNS: usize = 64;
+
+const BECH32_CHARSET: &'static [u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GEN: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk = 1u32;
+    for v in values {
+        let b = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ (*v as u32);
+        for (i, g) in GEN.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= *g;
+            }
+        }
+    }
+    chk
+}
+
+fn bech32_hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut result = hrp.bytes().map(|b| b >> 5).collect::<Vec<_>>();
+    result.push(0);
+    result.extend(hrp.bytes().map(|b| b & 31));
+    result
+}
+
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc = 0u32;
+    let mut bits = 0u32;
+    let mut result = Vec::new();
+    let maxv = (1 << to) - 1;
+    for v in data {
+        acc = (acc << from) | (*v as u32);
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((acc >> bits) & maxv) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            result.push(((acc << (to - bits)) & maxv) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & maxv) != 0 {
+        return None;
+    }
+    Some(result)
+}
+
+/// Bech32 without the 90 character limit, like age does.
+fn bech32_encode(hrp: &str, data: &[u8]) -> String {
+    let values = convert_bits(data, 8, 5, true).unwrap();
+    let mut checksum_input = bech32_hrp_expand(hrp);
+    checksum_input.extend(&values);
+    checksum_input.extend(&[0; 6]);
+    let checksum = bech32_polymod(&checksum_input) ^ 1;
+    let mut result = format!("{}1", hrp);
+    for v in values.iter().cloned().chain((0..6).map(|i| ((checksum >> (5 * (5 - i))) & 31) as u8)) {
+        result.push(BECH32_CHARSET[v as usize] as char);
+    }
+    result
+}
+
+fn bech32_decode(hrp: &str, text: &str) -> Result<Vec<u8>> {
+    if text.to_lowercase() != text && text.to_uppercase() != text {
+        return Err(Error::InvalidAgeFormat);
+    }
+    let text = text.to_lowercase();
+    let sep = text.rfind('1').ok_or(Error::InvalidAgeFormat)?;
+    if &text[..sep] != hrp || text.len() < sep + 7 {
+        return Err(Error::InvalidAgeFormat);
+    }
+    let values = text[sep + 1..].bytes()
+        .map(|c| BECH32_CHARSET.iter().position(|x| *x == c).map(|p| p as u8))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(Error::InvalidAgeFormat)?;
+    let mut checksum_input = bech32_hrp_expand(hrp);
+    checksum_input.extend(&values);
+    if bech32_polymod(&checksum_input) != 1 {
+        return Err(Error::InvalidAgeFormat);
+    }
+    convert_bits(&values[..values.len() - 6], 5, 8, false).ok_or(Error::InvalidAgeFormat)
+}
+
+/// age uses base64 without padding, and only accepts the canonical encoding.
+fn encode_b64(data: &[u8]) -> String {
+    base64::encode(data).trim_right_matches('=').to_owned()
+}
+
+fn decode_b64(text: &str) -> Result<Vec<u8>> {
+    if text.contains('=') {
+        return Err(Error::InvalidAgeFormat);
+    }
+    let padding = "==".get(..(4 - text.len() % 4) % 4).ok_or(Error::InvalidAgeFormat)?;
+    let data = base64::decode(&format!("{}{}", text, padding)).map_err(|_| Error::InvalidAgeFormat)?;
+    if encode_b64(&data) != text {
+        return Err(Error::InvalidAgeFormat);
+    }
+    Ok(data)
+}
+
+fn hkdf_sha256(ikm: &[u8], salt: &[u8], info: &[u8]) -> SecStr {
+    let mut prk = [0; 32];
+    hkdf_extract(Sha256::new(), salt, ikm, &mut prk);
+    let mut okm = vec![0; 32];
+    hkdf_expand(Sha256::new(), &prk, info, &mut okm);
+    SecStr::new(okm)
+}
+
+fn aead_key(key: &SecStr) -> aead::Key {
+    aead::Key::from_slice(key.unsecure()).unwrap()
+}
+
+/// An age X25519 recipient (`age1…`), derived or given by someone else.
+#[derive(PartialEq, Clone, Debug)]
+pub struct AgeRecipient(pub curve25519::GroupElement);
+
+impl AgeRecipient {
+    pub fn from_keypair(keypair: &Output) -> Result<AgeRecipient> {
+        if let &Output::X25519Keypair(ref pubkey, _) = keypair {
+            Ok(AgeRecipient(pubkey.clone()))
+        } else {
+            Err(Error::InappropriateFormat)
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<AgeRecipient> {
+        let data = bech32_decode(AGE_RECIPIENT_HRP, text.trim())?;
+        Ok(AgeRecipient(curve25519::GroupElement::from_slice(&data).ok_or(Error::InvalidAgeFormat)?))
+    }
+
+    pub fn to_string(&self) -> String {
+        bech32_encode(AGE_RECIPIENT_HRP, &self.0[..])
+    }
+}
+
+/// Turns the site seed into an identity. The seed is hashed first,
+/// so the key isn't the same as the `RawKey` of the seed or any other key derived from it.
+pub fn age_keypair(seed: &SecStr) -> Result<Output> {
+    if seed.unsecure().len() != curve25519::SCALARBYTES {
+        return Err(Error::WrongDerivedKeyLength);
+    }
+    let seckey = curve25519::Scalar::from_slice(&blake2b(seed.unsecure(), b"freepass.age", curve25519::SCALARBYTES))
+        .ok_or(Error::WrongDerivedKeyLength)?;
+    Ok(Output::X25519Keypair(curve25519::scalarmult_base(&seckey), seckey))
+}
+
+pub fn age_recipient_output(keypair: &Output) -> Result<String> {
+    Ok(AgeRecipient::from_keypair(keypair)?.to_string())
+}
+
+/// The identity in the format of `age-keygen` (`AGE-SECRET-KEY-1…`).
+pub fn age_identity_output(keypair: &Output) -> Result<SecStr> {
+    if let &Output::X25519Keypair(_, ref seckey) = keypair {
+        Ok(SecStr::from(bech32_encode(AGE_IDENTITY_HRP, &seckey[..]).to_uppercase()))
+    } else {
+        Err(Error::InappropriateFormat)
+    }
+}
+
+fn x25519_wrap_key(shared: &curve25519::GroupElement, ephemeral_share: &[u8], recipient: &[u8]) -> SecStr {
+    let mut salt = ephemeral_share.to_vec();
+    salt.extend(recipient);
+    hkdf_sha256(&shared[..], &salt, AGE_X25519_INFO)
+}
+
+fn header_mac(file_key: &SecStr, header: &[u8]) -> Vec<u8> {
+    hmac(Sha256::new(), hkdf_sha256(file_key.unsecure(), b"", b"header").unsecure(), header)
+}
+
+fn chunk_nonce(counter: u64, last: bool) -> aead::Nonce {
+    let mut nonce = [0; aead::NONCEBYTES];
+    for i in 0..8 {
+        nonce[3 + i] = (counter >> (8 * (7 - i))) as u8;
+    }
+    nonce[aead::NONCEBYTES - 1] = if last { 1 } else { 0 };
+    aead::Nonce(nonce)
+}
+
+/// Encrypts the data to the recipients, returns a binary age file.
+pub fn age_encrypt(recipients: &[AgeRecipient], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut rng = OsRng::new()?;
+    let mut file_key = vec![0; FILE_KEY_BYTES];
+    rng.fill_bytes(&mut file_key);
+    let mut ephemeral_secrets = Vec::new();
+    for _ in recipients {
+        let mut secret = [0; curve25519::SCALARBYTES];
+        rng.fill_bytes(&mut secret);
+        ephemeral_secrets.push(curve25519::Scalar(secret));
+    }
+    let mut nonce = [0; PAYLOAD_NONCE_BYTES];
+    rng.fill_bytes(&mut nonce);
+    age_encrypt_with(recipients, plaintext, &SecStr::new(file_key), &ephemeral_secrets, &nonce)
+}
+
+fn age_encrypt_with(recipients: &[AgeRecipient], plaintext: &[u8], file_key: &SecStr,
+                    ephemeral_secrets: &[curve25519::Scalar], nonce: &[u8]) -> Result<Vec<u8>> {
+    if recipients.len() == 0 {
+        return Err(Error::InappropriateFormat);
+    }
+    let mut header = format!("{}\n", AGE_VERSION_LINE);
+    for (recipient, ephemeral_secret) in recipients.iter().zip(ephemeral_secrets.iter()) {
+        let ephemeral_share = curve25519::scalarmult_base(ephemeral_secret);
+        let shared = curve25519::scalarmult(ephemeral_secret, &recipient.0).map_err(|_| Error::InvalidAgeFormat)?;
+        let wrap_key = x25519_wrap_key(&shared, &ephemeral_share[..], &recipient.0[..]);
+        let body = aead::seal(file_key.unsecure(), None, &aead::Nonce([0; aead::NONCEBYTES]), &aead_key(&wrap_key));
+        // The wrapped key is shorter than a line, so the body is always a single line
+        header.push_str(&format!("-> X25519 {}\n{}\n", encode_b64(&ephemeral_share[..]), encode_b64(&body)));
+    }
+    header.push_str("---");
+    let mac = header_mac(file_key, header.as_bytes());
+    header.push_str(&format!(" {}\n", encode_b64(&mac)));
+
+    let mut result = header.into_bytes();
+    result.extend(nonce);
+    let payload_key = aead_key(&hkdf_sha256(file_key.unsecure(), nonce, b"payload"));
+    let chunk_count = if plaintext.len() == 0 { 1 } else { (plaintext.len() + CHUNK_SIZE - 1) / CHUNK_SIZE };
+    for i in 0..chunk_count {
+        let chunk = &plaintext[i * CHUNK_SIZE..plaintext.len().min((i + 1) * CHUNK_SIZE)];
+        result.extend(aead::seal(chunk, None, &chunk_nonce(i as u64, i == chunk_count - 1), &payload_key));
+    }
+    Ok(result)
+}
+
+struct Stanza {
+    args: Vec<String>,
+    body: Vec<u8>,
+}
+
+fn next_line<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a str> {
+    let len = data[*pos..].iter().position(|b| *b == b'\n').ok_or(Error::InvalidAgeFormat)?;
+    let line = str::from_utf8(&data[*pos..*pos + len]).map_err(|_| Error::InvalidAgeFormat)?;
+    *pos += len + 1;
+    Ok(line)
+}
+
+/// Returns the stanzas, the header bytes covered by the MAC, the MAC and the payload.
+fn parse_header(data: &[u8]) -> Result<(Vec<Stanza>, &[u8], Vec<u8>, &[u8])> {
+    let mut pos = 0;
+    if next_line(data, &mut pos)? != AGE_VERSION_LINE {
+        return Err(Error::InvalidAgeFormat);
+    }
+    let mut stanzas = Vec::new();
+    loop {
+        let line_start = pos;
+        let line = next_line(data, &mut pos)?;
+        if line.starts_with("---") {
+            if stanzas.len() == 0 || !line.starts_with("--- ") {
+                return Err(Error::InvalidAgeFormat);
+            }
+            let mac = decode_b64(&line[4..])?;
+            if mac.len() != HEADER_MAC_BYTES {
+                return Err(Error::InvalidAgeFormat);
+            }
+            // A passphrase can't be combined with other recipients
+            if stanzas.len() > 1 && stanzas.iter().any(|s: &Stanza| s.args[0] == "scrypt") {
+                return Err(Error::InvalidAgeFormat);
+            }
+            return Ok((stanzas, &data[..line_start + 3], mac, &data[pos..]));
+        }
+        if !line.starts_with("-> ") {
+            return Err(Error::InvalidAgeFormat);
+        }
+        let args = line[3..].split(' ').map(|a| a.to_owned()).collect::<Vec<_>>();
+        if args.iter().any(|a| a.len() == 0 || !a.bytes().all(|b| b >= 0x21 && b <= 0x7e)) {
+            return Err(Error::InvalidAgeFormat);
+        }
+        let mut body = Vec::new();
+        loop {
+            let line = next_line(data, &mut pos)?;
+            if line.len() > COLUMNS {
+                return Err(Error::InvalidAgeFormat);
+            }
+            body.extend(decode_b64(line)?);
+            if line.len() < COLUMNS {
+                break;
+            }
+        }
+        stanzas.push(Stanza { args: args, body: body });
+    }
+}
+
+fn unwrap_file_key(seckey: &curve25519::Scalar, pubkey: &curve25519::GroupElement, stanza: &Stanza) -> Result<Option<SecStr>> {
+    if stanza.args[0] != "X25519" {
+        return Ok(None);
+    }
+    if stanza.args.len() != 2 || stanza.body.len() != FILE_KEY_BYTES + aead::TAGBYTES {
+        return Err(Error::InvalidAgeFormat);
+    }
+    let ephemeral_share = decode_b64(&stanza.args[1])?;
+    let ephemeral_share_element = curve25519::GroupElement::from_slice(&ephemeral_share).ok_or(Error::InvalidAgeFormat)?;
+    let shared = curve25519::scalarmult(seckey, &ephemeral_share_element).map_err(|_| Error::InvalidAgeFormat)?;
+    let wrap_key = x25519_wrap_key(&shared, &ephemeral_share, &pubkey[..]);
+    Ok(aead::open(&stanza.body, None, &aead::Nonce([0; aead::NONCEBYTES]), &aead_key(&wrap_key)).ok().map(SecStr::new))
+}
+
+/// Decrypts a binary age file with the identity.
+pub fn age_decrypt(keypair: &Output, data: &[u8]) -> Result<SecStr> {
+    let (pubkey, seckey) = if let &Output::X25519Keypair(ref pubkey, ref seckey) = keypair {
+        (pubkey, seckey)
+    } else {
+        return Err(Error::InappropriateFormat);
+    };
+    let (stanzas, header, mac, payload) = parse_header(data)?;
+    let mut file_key = None;
+    for stanza in stanzas.iter() {
+        if let Some(key) = unwrap_file_key(seckey, pubkey, stanza)? {
+            file_key = Some(key);
+            break;
+        }
+    }
+    let file_key = file_key.ok_or(Error::AgeNoMatchingIdentity)?;
+    if !fixed_time_eq(&header_mac(&file_key, header), &mac) {
+        return Err(Error::DecryptionError);
+    }
+    if payload.len() < PAYLOAD_NONCE_BYTES + aead::TAGBYTES {
+        return Err(Error::InvalidAgeFormat);
+    }
+    let (nonce, ciphertext) = payload.split_at(PAYLOAD_NONCE_BYTES);
+    let payload_key = aead_key(&hkdf_sha256(file_key.unsecure(), nonce, b"payload"));
+    let chunks = ciphertext.chunks(CHUNK_SIZE + aead::TAGBYTES).collect::<Vec<_>>();
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    for (i, chunk) in chunks.iter().enumerate() {
+        let last = i == chunks.len() - 1;
+        // Only an empty file has an empty last chunk
+        if last && i > 0 && chunk.len() == aead::TAGBYTES {
+            return Err(Error::InvalidAgeFormat);
+        }
+        plaintext.extend(aead::open(chunk, None, &chunk_nonce(i as u64, last), &payload_key).map_err(|_| Error::DecryptionError)?);
+    }
+    Ok(SecStr::new(plaintext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::digest::Digest;
+
+    fn keypair(seed: &[u8]) -> Output {
+        let seckey = curve25519::Scalar::from_slice(seed).unwrap();
+        Output::X25519Keypair(curve25519::scalarmult_base(&seckey), seckey)
+    }
+
+    const IDENTITY: &'static str = "AGE-SECRET-KEY-1V9SKZCTPV9SKZCTPV9SKZCTPV9SKZCTPV9SKZCTPV9SKZCTPV9SSC2RLUX";
+    const RECIPIENT: &'static str = "age1gpy4qtde9j3rgtpljtdvt4k70jzakh04gpa9kjvkecul9mahaqnspp4r62";
+    const OTHER_RECIPIENT: &'static str = "age1ff8cen0pnrtxaxd5cq2yrz3jy08z2mycjq9wff5prlgs7l4cfskq59vhv0";
+
+    const HEADER: &'static str = concat!(
+        "age-encryption.org/v1\n",
+        "-> X25519 +0Gx/PdcEif6CIdgVqXtB6xE/gwRbsLegLjlI5WdeCQ\n",
+        "KVxCN9CXEnCGVCU6vc5OnZb4HFLs2N6JvmgWboIU4eE\n",
+        "--- 31IM2MzF5L39quHngbhSofu6t9loZ/SjS2xbIvfLLGk\n"
+    );
+    const PAYLOAD: &'static str = "6e6e6e6e6e6e6e6e6e6e6e6e6e6e6e6e4a060368464251fa81d791b63b65d559f97f542098fc2c896d58857e";
+
+    // Encrypted to OTHER_RECIPIENT first, then to RECIPIENT
+    const TWO_RECIPIENTS_HEADER: &'static str = concat!(
+        "age-encryption.org/v1\n",
+        "-> X25519 bKO/Odl2qzaoCFMb1+wuS0+KrjqQE0U01Q9BHTd6LAU\n",
+        "jSbCeXrd8x5fbTJHboelxS7vKJlnOzFVta+U/9NNagI\n",
+        "-> X25519 2nhdmqPNyIwkAXYFNa1fQ23IOlQuKU+q8H+yJTsAlBY\n",
+        "TQgZim2BFRwnlp0fxagr1F/Y2vrJviLXDMBl2Qe6Oxw\n",
+        "--- GY9yv6hSQj6cyDIyCS1+g1vT1BP2gCssJBG9EqiChAs\n"
+    );
+    const TWO_RECIPIENTS_PAYLOAD: &'static str = "6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6df3442a69b2246ae09c7cf1979480a56f8847fbedeb3464ec50fa79ad";
+
+    // From the age test kit (https://github.com/C2SP/CCTV/tree/main/age)
+    const TESTKIT: &'static [(&'static str, &'static [u8])] = &[
+        ("hmac_bad", include_bytes!("age_testkit/hmac_bad")),
+        ("hmac_missing", include_bytes!("age_testkit/hmac_missing")),
+        ("scrypt_and_x25519", include_bytes!("age_testkit/scrypt_and_x25519")),
+        ("stanza_invalid_character", include_bytes!("age_testkit/stanza_invalid_character")),
+        ("stream_empty_payload", include_bytes!("age_testkit/stream_empty_payload")),
+        ("stream_no_final", include_bytes!("age_testkit/stream_no_final")),
+        ("x25519", include_bytes!("age_testkit/x25519")),
+        ("x25519_grease", include_bytes!("age_testkit/x25519_grease")),
+        ("x25519_low_order", include_bytes!("age_testkit/x25519_low_order")),
+        ("x25519_lowercase", include_bytes!("age_testkit/x25519_lowercase")),
+        ("x25519_multiple_recipients", include_bytes!("age_testkit/x25519_multiple_recipients")),
+        ("x25519_no_match", include_bytes!("age_testkit/x25519_no_match")),
+        ("x25519_not_canonical_share", include_bytes!("age_testkit/x25519_not_canonical_share")),
+    ];
+
+    /// Test kit files start with "key: value" lines, then an empty line, then the age file.
+    fn testkit_file(data: &[u8]) -> (Vec<(&str, &str)>, &[u8]) {
+        let split = (0..data.len()).find(|&i| data[i..].starts_with(b"\n\n")).unwrap();
+        let meta = str::from_utf8(&data[..split]).unwrap().lines().map(|line| {
+            let mut parts = line.splitn(2, ": ");
+            (parts.next().unwrap(), parts.next().unwrap())
+        }).collect();
+        (meta, &data[split + 2..])
+    }
+
+    fn testkit_value<'a>(meta: &[(&str, &'a str)], key: &str) -> &'a str {
+        meta.iter().find(|&&(k, _)| k == key).map(|&(_, v)| v).unwrap()
+    }
+
+    fn age_file(header: &str, payload: &str) -> Vec<u8> {
+        let mut result = header.as_bytes().to_vec();
+        result.extend(hex_decode(payload));
+        result
+    }
+
+    fn hex_decode(text: &str) -> Vec<u8> {
+        (0..text.len() / 2).map(|i| u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_bech32() {
+        assert_eq!(bech32_encode("a", b""), "a12uel5l");
+        assert_eq!(bech32_decode("a", "A12UEL5L").unwrap(), b"");
+        assert!(bech32_decode("a", "a12uel5m").is_err());
+        assert!(bech32_decode("a", "A12uel5l").is_err());
+    }
+
+    #[test]
+    fn test_keypair() {
+        let keypair = age_keypair(&SecStr::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")).unwrap();
+        assert_eq!(age_identity_output(&keypair).unwrap(), SecStr::from("AGE-SECRET-KEY-1L08WVWVAGVDXX2ZC5ULTNGAXW3H42VLP5MJ40T60LGJVKJRZT9FQ0ANN0R"));
+        assert_eq!(age_recipient_output(&keypair).unwrap(), "age1uzq9s7x8whu643d4atvxa5v8j4cmuu4sjwx3fn3j34392ecvsvrsh2apvt");
+        assert!(age_keypair(&SecStr::from("too short")).is_err());
+    }
+
+    #[test]
+    fn test_identity_and_recipient() {
+        let keypair = keypair(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        assert_eq!(age_identity_output(&keypair).unwrap(), SecStr::from(IDENTITY));
+        assert_eq!(age_recipient_output(&keypair).unwrap(), RECIPIENT);
+        assert_eq!(AgeRecipient::parse(RECIPIENT).unwrap(), AgeRecipient::from_keypair(&keypair).unwrap());
+        assert!(AgeRecipient::parse(&RECIPIENT.replace("age1g", "age1h")).is_err());
+        assert!(AgeRecipient::parse(IDENTITY).is_err());
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let recipient = AgeRecipient::parse(RECIPIENT).unwrap();
+        let ephemeral = curve25519::Scalar::from_slice(b"eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee").unwrap();
+        let file = age_encrypt_with(&[recipient], b"hello world\n", &SecStr::from("ffffffffffffffff"), &[ephemeral], b"nnnnnnnnnnnnnnnn").unwrap();
+        assert_eq!(file, age_file(HEADER, PAYLOAD));
+        assert!(age_encrypt(&[], b"hello world\n").is_err());
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let other_keypair = keypair(b"cccccccccccccccccccccccccccccccc");
+        let keypair = keypair(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        assert_eq!(age_decrypt(&keypair, &age_file(HEADER, PAYLOAD)).unwrap(), SecStr::from("hello world\n"));
+        assert_eq!(age_decrypt(&keypair, &age_file(TWO_RECIPIENTS_HEADER, TWO_RECIPIENTS_PAYLOAD)).unwrap(), SecStr::from("hello again\n"));
+        match age_decrypt(&other_keypair, &age_file(HEADER, PAYLOAD)) {
+            Err(Error::AgeNoMatchingIdentity) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // Header MAC
+        match age_decrypt(&keypair, &age_file(&HEADER.replace("31IM", "31IN"), PAYLOAD)) {
+            Err(Error::DecryptionError) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // Payload
+        match age_decrypt(&keypair, &age_file(HEADER, &PAYLOAD.replace("4a06", "4a07"))) {
+            Err(Error::DecryptionError) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // Truncated (the last chunk flag doesn't match)
+        let mut file = age_file(HEADER, PAYLOAD);
+        file.truncate(file.len() - 1);
+        assert!(age_decrypt(&keypair, &file).is_err());
+        assert!(age_decrypt(&keypair, b"age-encryption.org/v1\n--- 31IM2MzF5L39quHngbhSofu6t9loZ/SjS2xbIvfLLGk\n").is_err());
+    }
+
+    #[test]
+    fn test_testkit() {
+        for &(name, data) in TESTKIT {
+            let (meta, file) = testkit_file(data);
+            let seckey = bech32_decode(AGE_IDENTITY_HRP, testkit_value(&meta, "identity")).unwrap();
+            match (testkit_value(&meta, "expect"), age_decrypt(&keypair(&seckey), file)) {
+                ("success", Ok(plaintext)) => {
+                    let mut hash = Sha256::new();
+                    hash.input(plaintext.unsecure());
+                    assert_eq!(hash.result_str(), testkit_value(&meta, "payload"), "{}", name);
+                },
+                ("no match", Err(Error::AgeNoMatchingIdentity)) => (),
+                ("HMAC failure", Err(Error::DecryptionError)) => (),
+                ("header failure", Err(Error::InvalidAgeFormat)) => (),
+                ("payload failure", Err(_)) => (),
+                (expect, r) => panic!("{}: expected {}, got {:?}", name, expect, r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let keypair = keypair(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        let recipients = [AgeRecipient::parse(OTHER_RECIPIENT).unwrap(), AgeRecipient::from_keypair(&keypair).unwrap()];
+        for len in &[0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 2 * CHUNK_SIZE] {
+            let plaintext = (0..*len).map(|i| i as u8).collect::<Vec<_>>();
+            let file = age_encrypt(&recipients, &plaintext).unwrap();
+            assert_eq!(age_decrypt(&keypair, &file).unwrap(), SecStr::new(plaintext));
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! An ssh-agent that derives its keys on demand, instead of adding them to another agent.
+
+use std::{env, fs};
+use std::ffi::OsString;
+use std::io::{self, Cursor, Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use unix_socket::{UnixListener, UnixStream};
+use libc;
+use secstr::SecStr;
+use data::*;
+use output::*;
+use result::{Error, Result};
+
+/// How long a client can stay silent (or not read the response) before it's disconnected,
+/// so that one stuck client doesn't block the others while connections are served one by one.
+const CONNECTION_TIMEOUT_SECS: u64 = 10;
+
+/// A `Field::Derived` SSH key served by the agent.
+pub struct AgentIdentity {
+    pub entry_name: String,
+    pub field: Field,
+    /// Ask for confirmation before every signature
+    pub confirm: bool,
+    pub expires_at: Option<Instant>,
+}
+
+impl AgentIdentity {
+    pub fn new(entry_name: &str, field: Field, confirm: bool, lifetime: Option<Duration>) -> AgentIdentity {
+        AgentIdentity {
+            entry_name: entry_name.to_owned(),
+            field: field,
+            confirm: confirm,
+            expires_at: lifetime.map(|l| Instant::now() + l),
+        }
+    }
+}
+
+pub struct Agent {
+    master_key: SecStr,
+    identities: Vec<AgentIdentity>,
+    confirm: Box<Fn(&str) -> bool + Send>,
+}
+
+impl Agent {
+    /// `confirm` is called with the entry name when a key with the `confirm` flag is about to be used.
+    pub fn new(master_key: SecStr, confirm: Box<Fn(&str) -> bool + Send>) -> Agent {
+        Agent {
+            master_key: master_key,
+            identities: Vec::new(),
+            confirm: confirm,
+        }
+    }
+
+    /// Checks that the field is an SSH key and adds it.
+    pub fn add_identity(&mut self, identity: AgentIdentity) -> Result<()> {
+        self.keypair(&identity)?;
+        self.identities.push(identity);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.identities.len()
+    }
+
+    fn keypair(&self, identity: &AgentIdentity) -> Result<Output> {
+        match process_output(&identity.entry_name, &self.master_key, &identity.field)? {
+            output @ Output::Ed25519Keypair(Ed25519Usage::SSH, _, _) => Ok(output),
+            _ => Err(Error::InappropriateFormat),
+        }
+    }
+
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        self.identities.retain(|i| i.expires_at.map(|e| e > now).unwrap_or(true));
+    }
+
+    /// Handles one agent protocol message (without the length prefix), returns the response.
+    pub fn handle_message(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.remove_expired();
+        self.try_handle_message(msg).unwrap_or(vec![SSH_AGENT_FAILURE])
+    }
+
+    fn try_handle_message(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        let mut cursor = Cursor::new(msg);
+        match cursor.read_u8()? {
+            SSH2_AGENTC_REQUEST_IDENTITIES => {
+                let mut answer = vec![SSH2_AGENT_IDENTITIES_ANSWER];
+                answer.write_u32::<BigEndian>(self.identities.len() as u32)?;
+                for identity in self.identities.iter() {
+                    write_ssh_string(&mut answer, &ssh_public_key_blob(&self.keypair(identity)?)?)?;
+                    write_ssh_string(&mut answer, identity.entry_name.as_bytes())?;
+                }
+                Ok(answer)
+            },
+            SSH2_AGENTC_SIGN_REQUEST => {
+                let key_blob = read_ssh_string(&mut cursor)?;
+                let data = read_ssh_string(&mut cursor)?;
+                let _flags = cursor.read_u32::<BigEndian>()?; // Only for RSA
+                for identity in self.identities.iter() {
+                    let keypair = self.keypair(identity)?;
+                    if ssh_public_key_blob(&keypair)? != key_blob {
+                        continue;
+                    }
+                    if identity.confirm && !(self.confirm)(&identity.entry_name) {
+                        return Ok(vec![SSH_AGENT_FAILURE]);
+                    }
+                    if let Output::Ed25519Keypair(_, _, ref seckey) = keypair {
+                        let signature = ssh_ed25519_signature_blob(seckey, &data)?;
+                        let mut answer = vec![SSH2_AGENT_SIGN_RESPONSE];
+                        write_ssh_string(&mut answer, &signature)?;
+                        return Ok(answer);
+                    }
+                }
+                Ok(vec![SSH_AGENT_FAILURE])
+            },
+            _ => Ok(vec![SSH_AGENT_FAILURE]),
+        }
+    }
+
+    /// Answers requests from one client until it disconnects.
+    pub fn serve_connection(&mut self, mut stream: UnixStream) -> Result<()> {
+        loop {
+            let len = match stream.read_u32::<BigEndian>() {
+                Ok(len) => len as usize,
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(Error::from(err)),
+            };
+            if len > SSH_AGENT_MAX_MESSAGE_LEN {
+                return Err(Error::DataError);
+            }
+            let mut msg = vec![0; len];
+            stream.read_exact(&mut msg)?;
+            let response = self.handle_message(&msg);
+            stream.write_u32::<BigEndian>(response.len() as u32)?;
+            stream.write_all(&response)?;
+        }
+    }
+
+    /// Listens on a new socket (only accessible by the current user) and serves clients one by one, forever.
+    /// Connections from other users are closed right away, idle ones after `CONNECTION_TIMEOUT_SECS`.
+    pub fn serve<P: AsRef<Path>>(&mut self, socket_path: P) -> Result<()> {
+        let listener = bind_private(socket_path)?;
+        let uid = unsafe { libc::geteuid() };
+        for stream in listener.incoming() {
+            let stream = stream?;
+            // root can connect to any socket, the keys are still only for this user
+            match peer_uid(&stream) {
+                Ok(peer) if peer == uid => (),
+                _ => continue,
+            }
+            let timeout = Some(Duration::from_secs(CONNECTION_TIMEOUT_SECS));
+            if stream.set_read_timeout(timeout).is_err() || stream.set_write_timeout(timeout).is_err() {
+                continue;
+            }
+            // A misbehaving client shouldn't bring the agent down
+            let _ = self.serve_connection(stream);
+        }
+        Ok(())
+    }
+}
+
+/// Creates a new directory only accessible by the current user in the temp directory
+/// (like OpenSSH's ssh-agent does), to put the socket into.
+pub fn private_socket_dir() -> Result<PathBuf> {
+    let mut template = env::temp_dir().join("freepass-agent.XXXXXX").into_os_string().into_vec();
+    template.push(0);
+    if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+        return Err(Error::from(io::Error::last_os_error()));
+    }
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+/// Binds and makes the socket accessible only by the current user.
+/// Others can't reach it before the chmod anyway when it's in a `private_socket_dir`.
+fn bind_private<P: AsRef<Path>>(socket_path: P) -> Result<UnixListener> {
+    let listener = UnixListener::bind(socket_path.as_ref())?;
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = ::std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if ret != 0 || len as usize != ::std::mem::size_of::<libc::ucred>() {
+        return Err(Error::from(io::Error::last_os_error()));
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t> {
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(Error::from(io::Error::last_os_error()));
+    }
+    Ok(uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{process, thread};
+    use sodiumoxide::crypto::sign::ed25519;
+
+    fn ssh_field(counter: u32) -> Field {
+        Field::Derived {
+            counter: counter,
+            site_name: None,
+            usage: DerivedUsage::Ed25519Key(Ed25519Usage::SSH),
+        }
+    }
+
+    fn request(client: &mut UnixStream, msg: &[u8]) -> Vec<u8> {
+        client.write_u32::<BigEndian>(msg.len() as u32).unwrap();
+        client.write_all(msg).unwrap();
+        let len = client.read_u32::<BigEndian>().unwrap();
+        let mut response = vec![0; len as usize];
+        client.read_exact(&mut response).unwrap();
+        response
+    }
+
+    fn sign_request(key_blob: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut msg = vec![SSH2_AGENTC_SIGN_REQUEST];
+        write_ssh_string(&mut msg, key_blob).unwrap();
+        write_ssh_string(&mut msg, data).unwrap();
+        msg.write_u32::<BigEndian>(0).unwrap();
+        msg
+    }
+
+    #[test]
+    fn test_agent_over_socket() {
+        let master_key = SecStr::from("not really a master key, but good enough for testing");
+        let key = process_output("example.com", &master_key, &ssh_field(1)).unwrap();
+        let confirm_key = process_output("example.org", &master_key, &ssh_field(1)).unwrap();
+        let mut agent = Agent::new(master_key, Box::new(|name: &str| name != "example.org"));
+        agent.add_identity(AgentIdentity::new("example.com", ssh_field(1), false, None)).unwrap();
+        agent.add_identity(AgentIdentity::new("example.org", ssh_field(1), true, None)).unwrap();
+        agent.add_identity(AgentIdentity::new("expired.example", ssh_field(1), false, Some(Duration::from_secs(0)))).unwrap();
+        assert!(agent.add_identity(AgentIdentity::new("example.net", Field::Derived { counter: 1, site_name: None, usage: DerivedUsage::RawKey }, false, None)).is_err());
+
+        let socket_path = env::temp_dir().join(format!("freepass-agent-test-{}.sock", process::id()));
+        let _ = fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            agent.serve_connection(stream).unwrap();
+        });
+        let mut client = UnixStream::connect(&socket_path).unwrap();
+
+        let mut expected_identities = vec![SSH2_AGENT_IDENTITIES_ANSWER, 0, 0, 0, 2];
+        write_ssh_string(&mut expected_identities, &ssh_public_key_blob(&key).unwrap()).unwrap();
+        write_ssh_string(&mut expected_identities, b"example.com").unwrap();
+        write_ssh_string(&mut expected_identities, &ssh_public_key_blob(&confirm_key).unwrap()).unwrap();
+        write_ssh_string(&mut expected_identities, b"example.org").unwrap();
+        assert_eq!(request(&mut client, &[SSH2_AGENTC_REQUEST_IDENTITIES]), expected_identities);
+
+        let response = request(&mut client, &sign_request(&ssh_public_key_blob(&key).unwrap(), b"hello world"));
+        let mut cursor = Cursor::new(&response[..]);
+        assert_eq!(cursor.read_u8().unwrap(), SSH2_AGENT_SIGN_RESPONSE);
+        let mut signature = Cursor::new(read_ssh_string(&mut cursor).unwrap());
+        assert_eq!(read_ssh_string(&mut signature).unwrap(), b"ssh-ed25519");
+        let sig = ed25519::Signature::from_slice(&read_ssh_string(&mut signature).unwrap()).unwrap();
+        if let Output::Ed25519Keypair(_, ref pubkey, _) = key {
+            assert!(ed25519::verify_detached(&sig, b"hello world", pubkey));
+        }
+
+        // Confirmation denied
+        assert_eq!(request(&mut client, &sign_request(&ssh_public_key_blob(&confirm_key).unwrap(), b"hello world")), vec![SSH_AGENT_FAILURE]);
+        // Unknown message
+        assert_eq!(request(&mut client, &[42]), vec![SSH_AGENT_FAILURE]);
+
+        drop(client);
+        server.join().unwrap();
+        fs::remove_file(&socket_path).unwrap();
+    }
+
+    #[test]
+    fn test_private_socket() {
+        let dir = private_socket_dir().unwrap();
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        let socket_path = dir.join("agent.sock");
+        let listener = bind_private(&socket_path).unwrap();
+        assert_eq!(fs::metadata(&socket_path).unwrap().permissions().mode() & 0o777, 0o600);
+        let _client = UnixStream::connect(&socket_path).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert_eq!(peer_uid(&stream).unwrap(), unsafe { libc::geteuid() });
+        fs::remove_file(&socket_path).unwrap();
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use secstr::SecStr;
+use result::{Error, Result};
+use util::blake2b;
+
+const ENGLISH_WORDS: &'static str = include_str!("bip39_english.txt");
+
+fn wordlist() -> Vec<&'static str> {
+    ENGLISH_WORDS.lines().collect()
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut result = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result(&mut result);
+    result
+}
+
+fn bits_of(bytes: &[u8], count: usize) -> Vec<bool> {
+    (0..count).map(|i| (bytes[i / 8] >> (7 - i % 8)) & 1 == 1).collect()
+}
+
+/// Number of entropy bytes for a mnemonic with this number of words.
+pub fn entropy_len(words: u8) -> Result<usize> {
+    if words < 12 || words > 24 || words % 3 != 0 {
+        return Err(Error::InvalidMnemonic);
+    }
+    Ok(words as usize / 3 * 4)
+}
+
+/// The entropy of a derived mnemonic. The mnemonic encodes it directly, so it's hashed
+/// instead of being a part of the site seed (which is also the seed of passwords, keys, etc.)
+pub fn derived_entropy(site_seed: &SecStr, words: u8) -> Result<SecStr> {
+    Ok(SecStr::new(blake2b(site_seed.unsecure(), b"freepass.bip39", entropy_len(words)?)))
+}
+
+/// Encodes 128 to 256 bits of entropy as a mnemonic (with the SHA-256 checksum).
+pub fn entropy_to_mnemonic(entropy: &[u8]) -> Result<SecStr> {
+    if entropy.len() < 16 || entropy.len() > 32 || entropy.len() % 4 != 0 {
+        return Err(Error::InvalidMnemonic);
+    }
+    let words = wordlist();
+    let mut bits = bits_of(entropy, entropy.len() * 8);
+    bits.extend(bits_of(&sha256(entropy), entropy.len() / 4));
+    let mut mnemonic = Vec::new();
+    for (i, word_bits) in bits.chunks(11).enumerate() {
+        let index = word_bits.iter().fold(0, |acc, &b| (acc << 1) | b as usize);
+        if i > 0 {
+            mnemonic.push(b' ');
+        }
+        mnemonic.extend(words[index].as_bytes());
+    }
+    Ok(SecStr::new(mnemonic))
+}
+
+/// Decodes a mnemonic back into entropy, validating the words and the checksum.
+pub fn mnemonic_to_entropy(mnemonic: &str) -> Result<SecStr> {
+    let words = wordlist();
+    let mut bits = Vec::new();
+    for word in mnemonic.split_whitespace() {
+        let index = words
+            .binary_search(&word.to_lowercase().as_str())
+            .map_err(|_| Error::InvalidMnemonic)?;
+        bits.extend((0..11).rev().map(|i| (index >> i) & 1 == 1));
+    }
+    let word_count = bits.len() / 11;
+    if word_count < 12 || word_count > 24 || word_count % 3 != 0 {
+        return Err(Error::InvalidMnemonic);
+    }
+    let entropy_bits = bits.len() * 32 / 33;
+    let entropy = SecStr::new(
+        bits[0..entropy_bits]
+            .chunks(8)
+            .map(|byte_bits| byte_bits.iter().fold(0, |acc, &b| (acc << 1) | b as u8))
+            .collect(),
+    );
+    if bits_of(&sha256(entropy.unsecure()), bits.len() - entropy_bits) != &bits[entropy_bits..] {
+        return Err(Error::InvalidMnemonic);
+    }
+    Ok(entropy)
+}
+
+/// Validates a mnemonic and returns it in the canonical form (lowercase, single spaces).
+pub fn normalize_mnemonic(mnemonic: &str) -> Result<SecStr> {
+    entropy_to_mnemonic(mnemonic_to_entropy(mnemonic)?.unsecure())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_vectors() {
+        let vectors = [
+            ("00000000000000000000000000000000", "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"),
+            ("7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f", "legal winner thank year wave sausage worth useful legal winner thank yellow"),
+            ("80808080808080808080808080808080", "letter advice cage absurd amount doctor acoustic avoid letter advice cage above"),
+            ("ffffffffffffffffffffffffffffffff", "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong"),
+            ("000000000000000000000000000000000000000000000000", "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon agent"),
+            ("0000000000000000000000000000000000000000000000000000000000000000", "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon art"),
+            ("9e885d952ad362caeb4efe34a8e91bd2", "ozone drill grab fiber curtain grace pudding thank cruise elder eight picnic"),
+        ];
+        for &(entropy, mnemonic) in vectors.iter() {
+            assert_eq!(entropy_to_mnemonic(&from_hex(entropy)).unwrap(), SecStr::from(mnemonic));
+            assert_eq!(mnemonic_to_entropy(mnemonic).unwrap(), SecStr::new(from_hex(entropy)));
+        }
+    }
+
+    #[test]
+    fn test_derived_entropy() {
+        let site_seed = SecStr::new((0..32).collect());
+        let entropy = derived_entropy(&site_seed, 24).unwrap();
+        assert_eq!(entropy.unsecure().len(), 32);
+        assert!(entropy != site_seed);
+        let short_entropy = derived_entropy(&site_seed, 12).unwrap();
+        assert_eq!(short_entropy.unsecure().len(), 16);
+        assert!(!site_seed.unsecure().starts_with(short_entropy.unsecure()));
+        assert!(derived_entropy(&site_seed, 13).is_err());
+    }
+
+    #[test]
+    fn test_validation() {
+        assert_eq!(
+            normalize_mnemonic(" Legal winner thank year wave  sausage worth useful legal winner thank YELLOW\n").unwrap(),
+            SecStr::from("legal winner thank year wave sausage worth useful legal winner thank yellow")
+        );
+        // Wrong checksum
+        assert!(mnemonic_to_entropy("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon").is_err());
+        // Not a word
+        assert!(mnemonic_to_entropy("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon freepass").is_err());
+        // Wrong length
+        assert!(mnemonic_to_entropy("abandon abandon abandon about").is_err());
+    }
+}
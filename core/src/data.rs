@@ -1,6 +1,10 @@
 use secstr::SecStr;
 use chrono::{DateTime, UTC};
+use serde_bytes;
+use rand::{self, RngCore};
+use util::blake2b;
 use std::collections::btree_map::BTreeMap;
+use std::collections::btree_set::BTreeSet;
 
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct EntryMetadata {
@@ -10,14 +14,121 @@ pub struct EntryMetadata {
     pub updated_at: DateTime<UTC>,
     #[serde(default)]
     pub tags: Vec<String>,
+    /// The current version of every field (and of removed ones), for merging
+    #[serde(default)]
+    pub field_versions: BTreeMap<String, FieldVersion>,
+    /// Stays the same when the entry is renamed, so merges can follow renames
+    #[serde(default)]
+    pub id: String,
+    #[serde(with = "serde_opt_date_freepass", default)]
+    pub renamed_at: Option<DateTime<UTC>>,
 }
 
 impl Default for EntryMetadata {
     fn default() -> EntryMetadata {
-        EntryMetadata { created_at: UTC::now(), updated_at: UTC::now(), tags: Vec::new() }
+        EntryMetadata {
+            created_at: UTC::now(),
+            updated_at: UTC::now(),
+            tags: Vec::new(),
+            field_versions: BTreeMap::new(),
+            id: new_entry_id(),
+            renamed_at: None,
+        }
+    }
+}
+
+impl EntryMetadata {
+    /// Records a new version for every field that differs from the old entry,
+    /// unless the caller has already set a different version than the old one (e.g. when merging).
+    /// Unchanged fields written before field versions existed get the old entry's time as their version,
+    /// otherwise they would look changed along with the entry when merging.
+    pub fn track_field_changes(&mut self, old: Option<(&Entry, &EntryMetadata)>, entry: &Entry, now: DateTime<UTC>) {
+        let (old_entry, old_metadata) = match old {
+            Some(x) => x,
+            None => {
+                for field_name in entry.fields.keys() {
+                    self.field_versions.entry(field_name.to_owned()).or_insert(FieldVersion { updated_at: now, based_on: None });
+                }
+                return;
+            },
+        };
+        let field_names = entry.fields.keys().chain(old_entry.fields.keys()).cloned().collect::<BTreeSet<_>>();
+        for field_name in field_names {
+            if entry.fields.get(&field_name) == old_entry.fields.get(&field_name) {
+                if entry.fields.contains_key(&field_name) && !old_metadata.field_versions.contains_key(&field_name) {
+                    self.field_versions.entry(field_name).or_insert(FieldVersion { updated_at: old_metadata.updated_at, based_on: None });
+                }
+                continue;
+            }
+            let old_version = old_metadata.field_versions.get(&field_name).cloned();
+            if self.field_versions.get(&field_name).cloned() != old_version {
+                continue;
+            }
+            let based_on = match old_version {
+                Some(version) => Some(version.updated_at),
+                // Written before field versions existed
+                None if old_entry.fields.contains_key(&field_name) => Some(old_metadata.updated_at),
+                None => None,
+            };
+            self.field_versions.insert(field_name, FieldVersion { updated_at: now, based_on: based_on });
+        }
+    }
+}
+
+/// A random (version 4) UUID.
+pub fn new_entry_id() -> String {
+    let mut bytes = [0; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format_uuid(bytes, 4)
+}
+
+/// The ID of an entry saved before entries had IDs.
+/// It's derived from the name and the creation time, so it's the same in every copy of the vault.
+pub fn legacy_entry_id(name: &str, created_at: &DateTime<UTC>) -> String {
+    let hash = blake2b(b"freepass.entry_id", format!("{}\n{}", name, created_at.to_rfc3339()).as_bytes(), 16);
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash);
+    format_uuid(bytes, 8)
+}
+
+fn format_uuid(mut bytes: [u8; 16], version: u8) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | (version << 4);
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Remembers that an entry was removed, so that merging an older copy of the vault doesn't bring it back.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// BLAKE2b of the entry name, the name itself isn't kept
+    #[serde(with = "serde_bytes")]
+    pub name_hash: Vec<u8>,
+    #[serde(with = "serde_date_freepass")]
+    pub deleted_at: DateTime<UTC>,
+    /// The removed entry's ID, so that other entries with the same name aren't affected
+    #[serde(default)]
+    pub entry_id: String,
+}
+
+impl Tombstone {
+    /// Tombstones written before entries had IDs are about any entry with the name.
+    pub fn is_for(&self, entry_id: &str) -> bool {
+        self.entry_id.is_empty() || self.entry_id == entry_id
     }
 }
 
+/// When a field was last changed (or removed), and which version that change was made on top of.
+/// This is what lets merges tell changes made one after another apart from concurrent ones.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct FieldVersion {
+    #[serde(with = "serde_date_freepass")]
+    pub updated_at: DateTime<UTC>,
+    /// `None` for new fields
+    #[serde(with = "serde_opt_date_freepass", default)]
+    pub based_on: Option<DateTime<UTC>>,
+}
+
 #[derive(PartialEq, Clone, Default, Debug, Serialize, Deserialize)]
 pub struct Entry {
     pub fields: BTreeMap<String, Field>,
@@ -34,6 +145,16 @@ pub enum DerivedUsage {
     Password(PasswordTemplate),
     Ed25519Key(Ed25519Usage),
     RawKey,
+    /// A TOTP secret to enroll on a site instead of a random one, so it can be recovered later
+    TotpSecret,
+    /// A BIP39 mnemonic with this number of words (12, 18 or 24)
+    Bip39(u8),
+    /// An age identity (X25519 key) to encrypt files to
+    X25519Key,
+    /// An OpenPGP key (Ed25519 for signing, Cv25519 for encryption) with a fixed creation time
+    OpenPgp,
+    /// A WireGuard private key (clamped X25519)
+    WireGuard,
 }
 
 #[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
@@ -41,6 +162,7 @@ pub enum Ed25519Usage {
     SSH,
     Signify,
     SQRL,
+    Minisign,
 }
 
 #[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
@@ -48,6 +170,12 @@ pub enum StoredUsage {
     Text,
     Password,
     Attachments,
+    /// An `otpauth://` URI or a base32 secret
+    Totp,
+    /// Same as `Totp`, but the code depends on the counter instead of the time
+    Hotp { counter: u64 },
+    /// A BIP39 mnemonic (validated when used)
+    Bip39,
 }
 
 #[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
@@ -84,3 +212,28 @@ mod serde_date_freepass {
         }
     }
 }
+
+mod serde_opt_date_freepass {
+    use chrono::{DateTime, UTC};
+    use serde::{self, Deserialize, Serializer, Deserializer};
+    use serde_cbor::Value;
+
+    pub fn serialize<S>(date: &Option<DateTime<UTC>>, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        match *date {
+            Some(ref date) => serializer.serialize_some(&date.to_rfc3339()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<DateTime<UTC>>, D::Error>
+        where D: Deserializer<'de>
+    {
+        let v = Value::deserialize(deserializer)?;
+        match v {
+            Value::String(s) => s.parse().map(Some).map_err(serde::de::Error::custom),
+            _ => Ok(None),
+        }
+    }
+}
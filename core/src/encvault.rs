@@ -1,11 +1,13 @@
 use std::io;
 use std::boxed::Box;
 use std::collections::btree_map::BTreeMap;
+use std::time::Duration;
 use rand::{Rng, RngCore};
 use rand::os::OsRng;
 use sodiumoxide::crypto::secretbox::xsalsa20poly1305 as secbox;
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
 use crypto::aes;
-use chrono::UTC;
+use chrono::{DateTime, UTC};
 use serde_cbor;
 use serde_bytes;
 use secstr::SecStr;
@@ -15,6 +17,13 @@ use vault::{Vault, WritableVault};
 use data::*;
 use util::blake2b;
 
+/// The `EncryptedVault` version written by `DecryptedVault::save`.
+///
+/// - 0: AES-128-CTR, no authentication (still readable, upgraded on save)
+/// - 1: XChaCha20-Poly1305 with the version number as associated data
+/// - 2: same, plus the padding policy (also part of the associated data)
+pub const CURRENT_VAULT_VERSION: u16 = 2;
+
 #[derive(PartialEq, Debug, Serialize, Deserialize)]
 pub struct EncryptedEntry {
     #[serde(with = "serde_bytes")]
@@ -32,6 +41,8 @@ pub struct EncryptedVault {
     pub nonce: Vec<u8>,
     #[serde(with = "serde_bytes")]
     pub ciphertext: Vec<u8>,
+    #[serde(default)]
+    pub padding_policy: Option<PaddingPolicy>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,10 +50,35 @@ pub struct DecryptedVaultData {
     #[serde(with = "serde_bytes")]
     pub padding: Vec<u8>,
     pub entries: BTreeMap<String, EncryptedEntry>,
+    #[serde(default)]
+    pub tombstones: Vec<Tombstone>,
+}
+
+/// How much random junk `DecryptedVault::save` adds to the vault data,
+/// to make it harder to count the entries without opening the file.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum PaddingPolicy {
+    /// Pads the data up to the next power of two (but at least `min_size` bytes),
+    /// so all vaults within a bucket have exactly the same file size.
+    Bucketed { min_size: usize },
+    /// Adds a random number of bytes in the `[min, max)` range.
+    RandomRange { min: usize, max: usize },
+}
+
+impl Default for PaddingPolicy {
+    fn default() -> PaddingPolicy {
+        PaddingPolicy::RandomRange { min: 0, max: 1024 * 10 }
+    }
 }
 
+/// How long `DecryptedVault::save` keeps tombstones of removed entries by default.
+/// Copies of the vault that weren't merged within that time can bring the entries back.
+pub const DEFAULT_TOMBSTONE_LIFETIME_SECS: u64 = 180 * 24 * 60 * 60;
+
 pub struct DecryptedVault {
     pub data: DecryptedVaultData,
+    pub padding_policy: PaddingPolicy,
+    pub tombstone_lifetime: Duration,
     entries_key: SecStr,
     outer_key: SecStr,
 }
@@ -56,11 +92,20 @@ impl DecryptedVault {
             let entry_key_wrapped = gen_entry_key(&self.entries_key, name, ee.counter)?;
             let plainbytes = secbox::open(&ee.ciphertext, &nonce_wrapped, &entry_key_wrapped)
                 .map_err(|_| Error::DecryptionError)?;
-            Ok((plainbytes, ee.metadata.clone()))
+            let mut metadata = ee.metadata.clone();
+            if metadata.id.is_empty() {
+                metadata.id = legacy_entry_id(name, &metadata.created_at);
+            }
+            Ok((plainbytes, metadata))
         } else {
             Err(Error::EntryNotFound)
         }
     }
+
+    fn tombstone_position(&self, name: &str) -> Option<usize> {
+        let name_hash = tombstone_name_hash(name);
+        self.data.tombstones.iter().position(|t| t.name_hash == name_hash)
+    }
 }
 
 impl Vault for DecryptedVault {
@@ -78,42 +123,57 @@ impl Vault for DecryptedVault {
         let entry = serde_cbor::from_slice(plaintext.unsecure())?;
         Ok((entry, metadata))
     }
+
+    fn tombstone(&self, name: &str) -> Option<Tombstone> {
+        self.tombstone_position(name).map(|i| self.data.tombstones[i].clone())
+    }
 }
 
 impl WritableVault for DecryptedVault {
     fn put_entry(&mut self, name: &str, entry: &Entry, metadata: &mut EntryMetadata) -> Result<()> {
+        let now = UTC::now();
+        let old = self.get_entry(name).ok();
+        metadata.track_field_changes(old.as_ref().map(|&(ref e, ref m)| (e, m)), entry, now);
+        metadata.updated_at = now;
+        self.put_merged_entry(name, entry, metadata)
+    }
+
+    fn remove_entry(&mut self, name: &str) {
+        self.remove_merged_entry(name, UTC::now())
+    }
+
+    fn put_merged_entry(&mut self, name: &str, entry: &Entry, metadata: &EntryMetadata) -> Result<()> {
         let counter = self.data
             .entries
             .get(name)
             .map(|ee| ee.counter + 1)
             .unwrap_or(1);
-        let nonce_wrapped = secbox::gen_nonce();
-        let secbox::Nonce(nonce) = nonce_wrapped;
-        let entry_key_wrapped = gen_entry_key(&self.entries_key, name, counter)?;
         let plaintext = SecStr::new(serde_cbor::to_vec(&entry)?);
-        let ciphertext = secbox::seal(plaintext.unsecure(), &nonce_wrapped, &entry_key_wrapped);
-        metadata.updated_at = UTC::now();
-        self.data.entries.insert(
-            name.to_owned(),
-            EncryptedEntry {
-                nonce: nonce.to_vec(),
-                counter: counter,
-                ciphertext: ciphertext,
-                metadata: metadata.clone(),
-            },
-        );
+        let encrypted_entry = encrypt_entry(&self.entries_key, name, counter, &plaintext, metadata.clone())?;
+        self.data.entries.insert(name.to_owned(), encrypted_entry);
+        if let Some(i) = self.tombstone_position(name) {
+            self.data.tombstones.remove(i);
+        }
         Ok(())
     }
 
-    fn remove_entry(&mut self, name: &str) {
-        self.data.entries.remove(name);
+    fn remove_merged_entry(&mut self, name: &str, deleted_at: DateTime<UTC>) {
+        if let Some(ee) = self.data.entries.remove(name) {
+            if let Some(i) = self.tombstone_position(name) {
+                self.data.tombstones.remove(i);
+            }
+            let entry_id = if ee.metadata.id.is_empty() { legacy_entry_id(name, &ee.metadata.created_at) } else { ee.metadata.id };
+            self.data.tombstones.push(Tombstone { name_hash: tombstone_name_hash(name), deleted_at: deleted_at, entry_id: entry_id });
+        }
     }
 }
 
 impl DecryptedVault {
     pub fn new(entries_key: SecStr, outer_key: SecStr) -> DecryptedVault {
         DecryptedVault {
-            data: DecryptedVaultData { padding: b"".to_vec(), entries: BTreeMap::new() },
+            data: DecryptedVaultData { padding: b"".to_vec(), entries: BTreeMap::new(), tombstones: Vec::new() },
+            padding_policy: PaddingPolicy::default(),
+            tombstone_lifetime: Duration::from_secs(DEFAULT_TOMBSTONE_LIFETIME_SECS),
             entries_key: entries_key,
             outer_key: outer_key,
         }
@@ -121,30 +181,146 @@ impl DecryptedVault {
 
     pub fn open<T: io::Read>(entries_key: SecStr, outer_key: SecStr, reader: T) -> Result<DecryptedVault> {
         let wrapper: EncryptedVault = serde_cbor::from_reader(reader)?;
-        let mut plaintext = SecStr::new(vec![0; wrapper.ciphertext.len()]);
-        aes::ctr(aes::KeySize::KeySize128, outer_key.unsecure(), &wrapper.nonce)
-            .process(&wrapper.ciphertext, &mut plaintext.unsecure_mut());
+        let plaintext = match wrapper.version {
+            0 => {
+                let mut plaintext = SecStr::new(vec![0; wrapper.ciphertext.len()]);
+                aes::ctr(aes::KeySize::KeySize128, outer_key.unsecure(), &wrapper.nonce)
+                    .process(&wrapper.ciphertext, &mut plaintext.unsecure_mut());
+                plaintext
+            },
+            1 | 2 => {
+                let nonce_wrapped = aead::Nonce::from_slice(&wrapper.nonce)
+                    .ok_or(Error::WrongOuterNonceLength)?;
+                let outer_aead_key = gen_outer_aead_key(&outer_key)?;
+                let ad = outer_ad(wrapper.version, &wrapper.padding_policy)?;
+                SecStr::new(
+                    aead::open(&wrapper.ciphertext, Some(&ad), &nonce_wrapped, &outer_aead_key)
+                        .map_err(|_| Error::OuterAuthenticationFailed)?,
+                )
+            },
+            _ => return Err(Error::UnsupportedVaultVersion),
+        };
         let data = serde_cbor::from_slice(plaintext.unsecure())?;
-        Ok(DecryptedVault { data: data, entries_key: entries_key, outer_key: outer_key })
+        Ok(DecryptedVault {
+            data: data,
+            padding_policy: wrapper.padding_policy.unwrap_or_default(),
+            tombstone_lifetime: Duration::from_secs(DEFAULT_TOMBSTONE_LIFETIME_SECS),
+            entries_key: entries_key,
+            outer_key: outer_key,
+        })
     }
 
+    /// Re-encrypts every entry (and, on the next save, the whole file) with new keys,
+    /// e.g. after changing the master password or the user name.
+    ///
+    /// Entries are copied as-is, so `Field::Derived` fields will produce *different* outputs
+    /// with the new master key. Convert them to `Field::Stored` first if the old outputs are still needed.
+    pub fn rekey(&mut self, new_entries_key: SecStr, new_outer_key: SecStr) -> Result<()> {
+        let mut new_entries = BTreeMap::new();
+        for (name, ee) in self.data.entries.iter() {
+            let (plainbytes, metadata) = self.get_entry_cbor(name)?;
+            let plaintext = SecStr::new(plainbytes);
+            new_entries.insert(name.to_owned(), encrypt_entry(&new_entries_key, name, ee.counter, &plaintext, metadata)?);
+        }
+        self.data.entries = new_entries;
+        self.entries_key = new_entries_key;
+        self.outer_key = new_outer_key;
+        Ok(())
+    }
+
+    /// Always writes the `CURRENT_VAULT_VERSION`, so older files are upgraded on the first save.
+    /// Tombstones older than `tombstone_lifetime` are dropped.
     pub fn save<T: io::Write>(&mut self, mut writer: T) -> Result<()> {
-        let mut plaintext = SecStr::new(serde_cbor::to_vec(&self.data)?);
         let mut rng = OsRng::new()?;
-        let padding_size = rng.gen_range(0, 1024 * 10);
+        let oldest_kept = UTC::now().timestamp() - self.tombstone_lifetime.as_secs() as i64;
+        self.data.tombstones.retain(|t| t.deleted_at.timestamp() >= oldest_kept);
+        let padding_size = match self.padding_policy {
+            PaddingPolicy::Bucketed { min_size } => {
+                self.data.padding = Vec::new();
+                bucketed_padding_size(serde_cbor::to_vec(&self.data)?.len(), min_size)
+            },
+            PaddingPolicy::RandomRange { min, max } if max > min => rng.gen_range(min, max),
+            PaddingPolicy::RandomRange { min, .. } => min,
+        };
         self.data.padding = vec![0; padding_size];
         rng.fill_bytes(&mut self.data.padding);
-        let mut iv = vec![0; 16];
-        rng.fill_bytes(&mut iv);
-        let mut ciphertext = vec![0; plaintext.unsecure().len()];
-        aes::ctr(aes::KeySize::KeySize128, self.outer_key.unsecure(), &iv)
-            .process(&plaintext.unsecure_mut(), &mut ciphertext);
-        let wrapper = EncryptedVault { version: 0, nonce: iv, ciphertext: ciphertext };
+        // The padding must be in place *before* serializing, otherwise it never reaches the file
+        let plaintext = SecStr::new(serde_cbor::to_vec(&self.data)?);
+        let nonce_wrapped = aead::gen_nonce();
+        let aead::Nonce(nonce) = nonce_wrapped;
+        let outer_aead_key = gen_outer_aead_key(&self.outer_key)?;
+        let padding_policy = Some(self.padding_policy);
+        let ad = outer_ad(CURRENT_VAULT_VERSION, &padding_policy)?;
+        let ciphertext = aead::seal(plaintext.unsecure(), Some(&ad), &nonce_wrapped, &outer_aead_key);
+        let wrapper = EncryptedVault {
+            version: CURRENT_VAULT_VERSION,
+            nonce: nonce.to_vec(),
+            ciphertext: ciphertext,
+            padding_policy: padding_policy,
+        };
         serde_cbor::ser::to_writer(&mut writer, &wrapper)?;
         Ok(())
     }
 }
 
+/// Length of a CBOR byte string with `len` bytes of content (header included).
+fn cbor_bytes_len(len: usize) -> usize {
+    len + match len {
+        0...23 => 1,
+        24...0xff => 2,
+        0x100...0xffff => 3,
+        0x10000...0xffffffff => 5,
+        _ => 9,
+    }
+}
+
+/// `unpadded_len` is the length of the serialized data with an empty padding byte string (which takes 1 byte).
+/// The padded length is always exactly a power of two.
+fn bucketed_padding_size(unpadded_len: usize, min_size: usize) -> usize {
+    let mut target_len = ::std::cmp::max(unpadded_len, min_size).next_power_of_two();
+    loop {
+        let padding_item_len = target_len - (unpadded_len - 1);
+        // A few item lengths (e.g. 25) can't be hit because of the header size jumps, use the next bucket then
+        if let Some(len) = (padding_item_len.saturating_sub(9)..padding_item_len + 1)
+            .find(|&len| cbor_bytes_len(len) == padding_item_len) {
+            return len;
+        }
+        target_len *= 2;
+    }
+}
+
+fn tombstone_name_hash(name: &str) -> Vec<u8> {
+    blake2b(b"freepass.tombstone", name.as_bytes(), 32)
+}
+
+/// The associated data of the outer layer: the version number and (since version 2) the padding policy.
+fn outer_ad(version: u16, padding_policy: &Option<PaddingPolicy>) -> Result<Vec<u8>> {
+    let mut ad = vec![(version >> 8) as u8, version as u8];
+    if version >= 2 {
+        ad.extend_from_slice(&serde_cbor::to_vec(padding_policy)?);
+    }
+    Ok(ad)
+}
+
+/// The version 1 outer layer needs a 256-bit key, `outer_key` is only 128 bits (for AES-128 in version 0).
+fn gen_outer_aead_key(outer_key: &SecStr) -> Result<aead::Key> {
+    let key = SecStr::new(blake2b(outer_key.unsecure(), b"freepass.outer.aead", aead::KEYBYTES));
+    Ok(aead::Key::from_slice(key.unsecure()).ok_or(Error::WrongOuterKeyLength)?)
+}
+
+fn encrypt_entry(entries_key: &SecStr, name: &str, counter: u32, plaintext: &SecStr, metadata: EntryMetadata) -> Result<EncryptedEntry> {
+    let nonce_wrapped = secbox::gen_nonce();
+    let secbox::Nonce(nonce) = nonce_wrapped;
+    let entry_key_wrapped = gen_entry_key(entries_key, name, counter)?;
+    let ciphertext = secbox::seal(plaintext.unsecure(), &nonce_wrapped, &entry_key_wrapped);
+    Ok(EncryptedEntry {
+        nonce: nonce.to_vec(),
+        counter: counter,
+        ciphertext: ciphertext,
+        metadata: metadata,
+    })
+}
+
 fn gen_entry_key(entries_key: &SecStr, name: &str, counter: u32) -> Result<secbox::Key> {
     let entry_key = gen_site_seed(entries_key, name, counter).map_err(|_| {
             Error::SeedGenerationError
@@ -167,6 +343,8 @@ pub fn gen_entries_key(master_key: &SecStr) -> SecStr {
 mod tests {
     use super::*;
     use rusterpassword::*;
+    use output::*;
+    use otp::{encode_secret, OtpParams};
 
     fn example_entry() -> Entry {
         let mut twitter = Entry::default();
@@ -216,4 +394,217 @@ mod tests {
         let loaded_vault = DecryptedVault::open(gen_entries_key(&master_key), gen_outer_key(&master_key), &storage[..]);
         assert!(loaded_vault.unwrap().data.entries == vault.data.entries);
     }
+
+    #[test]
+    fn test_rekey() {
+        let old_master_key = gen_master_key(SecStr::from("Correct Horse Battery Staple"), "Clarke Griffin").unwrap();
+        let new_master_key = gen_master_key(SecStr::from("Incorrect Donkey Cell Paperclip"), "Clarke Griffin").unwrap();
+        let mut vault = DecryptedVault::new(gen_entries_key(&old_master_key), gen_outer_key(&old_master_key));
+        let mut metadata = EntryMetadata::default();
+        vault.put_entry("twitter", &example_entry(), &mut metadata).unwrap();
+        vault.rekey(gen_entries_key(&new_master_key), gen_outer_key(&new_master_key)).unwrap();
+        assert!(vault.get_entry("twitter").unwrap() == (example_entry(), metadata.clone()));
+        let mut storage = Vec::new();
+        vault.save(&mut storage).unwrap();
+        assert!(DecryptedVault::open(gen_entries_key(&old_master_key), gen_outer_key(&old_master_key), &storage[..]).is_err());
+        let loaded_vault = DecryptedVault::open(gen_entries_key(&new_master_key), gen_outer_key(&new_master_key), &storage[..]).unwrap();
+        assert!(loaded_vault.get_entry("twitter").unwrap() == (example_entry(), metadata));
+    }
+
+    #[test]
+    fn test_rekey_snapshots() {
+        let old_master_key = gen_master_key(SecStr::from("Correct Horse Battery Staple"), "Clarke Griffin").unwrap();
+        let new_master_key = gen_master_key(SecStr::from("Incorrect Donkey Cell Paperclip"), "Clarke Griffin").unwrap();
+        let mut vault = DecryptedVault::new(gen_entries_key(&old_master_key), gen_outer_key(&old_master_key));
+        let mut entry = Entry::default();
+        for &(field_name, usage) in [("password", DerivedUsage::Password(PasswordTemplate::Long)), ("mnemonic", DerivedUsage::Bip39(24)), ("totp", DerivedUsage::TotpSecret)].iter() {
+            assert!(is_snapshottable(&usage));
+            entry.fields.insert(field_name.to_owned(), Field::Derived { counter: 1, site_name: None, usage: usage });
+        }
+        let old_outputs = entry.fields.iter()
+            .map(|(field_name, field)| (field_name.clone(), process_output("twitter", &old_master_key, field).unwrap()))
+            .collect::<BTreeMap<_, _>>();
+        for field in entry.fields.values_mut() {
+            *field = snapshot_derived_field("twitter", &old_master_key, field).unwrap();
+        }
+        vault.put_entry("twitter", &entry, &mut EntryMetadata::default()).unwrap();
+        vault.rekey(gen_entries_key(&new_master_key), gen_outer_key(&new_master_key)).unwrap();
+        let rekeyed = vault.get_entry("twitter").unwrap().0;
+        match (process_output("twitter", &new_master_key, &rekeyed.fields["password"]).unwrap(), &old_outputs["password"]) {
+            (Output::PrivateText(ref new), &Output::PrivateText(ref old)) => assert_eq!(new, old),
+            _ => panic!("Password not snapshotted"),
+        }
+        match (&rekeyed.fields["mnemonic"], &old_outputs["mnemonic"]) {
+            (&Field::Stored { ref data, usage: StoredUsage::Bip39 }, &Output::PrivateText(ref old)) => assert_eq!(data, old),
+            _ => panic!("Mnemonic not snapshotted"),
+        }
+        match (&rekeyed.fields["totp"], &old_outputs["totp"]) {
+            (&Field::Stored { ref data, usage: StoredUsage::Totp }, &Output::TotpSecret(ref enrollment)) =>
+                assert_eq!(encode_secret(OtpParams::parse_totp(data.unsecure()).unwrap().secret.unsecure()), enrollment.secret),
+            _ => panic!("TOTP secret not snapshotted"),
+        }
+        let key = Field::Derived { counter: 1, site_name: None, usage: DerivedUsage::Ed25519Key(Ed25519Usage::SSH) };
+        assert!(snapshot_derived_field("twitter", &old_master_key, &key).is_err());
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let master_key = gen_master_key(SecStr::from("Correct Horse Battery Staple"), "Clarke Griffin").unwrap();
+        let mut vault = DecryptedVault::new(gen_entries_key(&master_key), gen_outer_key(&master_key));
+        vault.put_entry("twitter", &example_entry(), &mut EntryMetadata::default()).unwrap();
+        assert!(vault.tombstone("twitter").is_none());
+        let id = vault.get_entry("twitter").unwrap().1.id;
+        vault.remove_entry("twitter");
+        vault.remove_entry("nonexistent");
+        assert_eq!(vault.tombstone("twitter").unwrap().entry_id, id);
+        assert!(vault.tombstone("nonexistent").is_none());
+        let mut storage = Vec::new();
+        vault.save(&mut storage).unwrap();
+        let mut loaded_vault = DecryptedVault::open(gen_entries_key(&master_key), gen_outer_key(&master_key), &storage[..]).unwrap();
+        assert_eq!(loaded_vault.tombstone("twitter"), vault.tombstone("twitter"));
+        // Putting the entry back forgets the removal
+        loaded_vault.put_entry("twitter", &example_entry(), &mut EntryMetadata::default()).unwrap();
+        assert!(loaded_vault.tombstone("twitter").is_none());
+        // Old tombstones are dropped on save
+        vault.data.tombstones[0].deleted_at = UTC::now() - ::chrono::Duration::days(200);
+        saved_len(&mut vault);
+        assert!(vault.tombstone("twitter").is_none());
+    }
+
+    #[test]
+    fn test_legacy_entry_id() {
+        let master_key = gen_master_key(SecStr::from("Correct Horse Battery Staple"), "Clarke Griffin").unwrap();
+        let mut vault = DecryptedVault::new(gen_entries_key(&master_key), gen_outer_key(&master_key));
+        vault.put_entry("twitter", &example_entry(), &mut EntryMetadata::default()).unwrap();
+        vault.data.entries.get_mut("twitter").unwrap().metadata.id = String::new();
+        let (_, metadata) = vault.get_entry("twitter").unwrap();
+        assert_eq!(metadata.id, legacy_entry_id("twitter", &metadata.created_at));
+        assert_eq!(metadata.id.len(), 36);
+        // Other copies of the vault get the same ID
+        let mut storage = Vec::new();
+        vault.save(&mut storage).unwrap();
+        let loaded_vault = DecryptedVault::open(gen_entries_key(&master_key), gen_outer_key(&master_key), &storage[..]).unwrap();
+        assert_eq!(loaded_vault.get_entry("twitter").unwrap().1.id, metadata.id);
+        assert!(new_entry_id() != new_entry_id());
+    }
+
+    fn saved_len(vault: &mut DecryptedVault) -> usize {
+        let mut storage = Vec::new();
+        vault.save(&mut storage).unwrap();
+        storage.len()
+    }
+
+    #[test]
+    fn test_padding_is_saved() {
+        let master_key = gen_master_key(SecStr::from("Correct Horse Battery Staple"), "Clarke Griffin").unwrap();
+        let mut vault = DecryptedVault::new(gen_entries_key(&master_key), gen_outer_key(&master_key));
+        vault.padding_policy = PaddingPolicy::RandomRange { min: 1, max: 1024 };
+        let mut storage = Vec::new();
+        vault.save(&mut storage).unwrap();
+        let loaded_vault = DecryptedVault::open(gen_entries_key(&master_key), gen_outer_key(&master_key), &storage[..]).unwrap();
+        assert!(loaded_vault.data.padding.len() > 0);
+        assert_eq!(loaded_vault.data.padding, vault.data.padding);
+    }
+
+    #[test]
+    fn test_padding_policy_is_saved() {
+        let master_key = gen_master_key(SecStr::from("Correct Horse Battery Staple"), "Clarke Griffin").unwrap();
+        let mut vault = DecryptedVault::new(gen_entries_key(&master_key), gen_outer_key(&master_key));
+        vault.padding_policy = PaddingPolicy::Bucketed { min_size: 4096 };
+        let mut storage = Vec::new();
+        vault.save(&mut storage).unwrap();
+        let mut loaded_vault = DecryptedVault::open(gen_entries_key(&master_key), gen_outer_key(&master_key), &storage[..]).unwrap();
+        assert_eq!(loaded_vault.padding_policy, PaddingPolicy::Bucketed { min_size: 4096 });
+        let mut resaved_storage = Vec::new();
+        loaded_vault.save(&mut resaved_storage).unwrap();
+        assert_eq!(resaved_storage.len(), storage.len());
+        let mut wrapper: EncryptedVault = serde_cbor::from_slice(&storage).unwrap();
+        wrapper.padding_policy = Some(PaddingPolicy::default());
+        let tampered = serde_cbor::to_vec(&wrapper).unwrap();
+        match DecryptedVault::open(gen_entries_key(&master_key), gen_outer_key(&master_key), &tampered[..]) {
+            Err(Error::OuterAuthenticationFailed) => (),
+            _ => panic!("Vault with a changed padding policy was not rejected"),
+        }
+    }
+
+    #[test]
+    fn test_random_padding_varies() {
+        let master_key = gen_master_key(SecStr::from("Correct Horse Battery Staple"), "Clarke Griffin").unwrap();
+        let mut vault = DecryptedVault::new(gen_entries_key(&master_key), gen_outer_key(&master_key));
+        vault.put_entry("twitter", &example_entry(), &mut EntryMetadata::default()).unwrap();
+        let lengths = (0..8).map(|_| saved_len(&mut vault)).collect::<::std::collections::BTreeSet<_>>();
+        assert!(lengths.len() > 1);
+    }
+
+    #[test]
+    fn test_bucketed_padding_hides_entry_count() {
+        let master_key = gen_master_key(SecStr::from("Correct Horse Battery Staple"), "Clarke Griffin").unwrap();
+        let policy = PaddingPolicy::Bucketed { min_size: 16 * 1024 };
+        let mut small_vault = DecryptedVault::new(gen_entries_key(&master_key), gen_outer_key(&master_key));
+        small_vault.padding_policy = policy;
+        small_vault.put_entry("twitter", &example_entry(), &mut EntryMetadata::default()).unwrap();
+        let mut big_vault = DecryptedVault::new(gen_entries_key(&master_key), gen_outer_key(&master_key));
+        big_vault.padding_policy = policy;
+        for i in 0..20 {
+            big_vault.put_entry(&format!("twitter{}", i), &example_entry(), &mut EntryMetadata::default()).unwrap();
+        }
+        let small_len = saved_len(&mut small_vault);
+        assert_eq!(small_len, saved_len(&mut small_vault));
+        assert_eq!(small_len, saved_len(&mut big_vault));
+    }
+
+    #[test]
+    fn test_bucketed_padding_size() {
+        for unpadded_len in 1..70000 {
+            let padding_size = bucketed_padding_size(unpadded_len, 1);
+            let padded_len = unpadded_len - 1 + cbor_bytes_len(padding_size);
+            assert!(padded_len >= unpadded_len);
+            assert!(padded_len.is_power_of_two());
+        }
+    }
+
+    #[test]
+    fn test_tampered_vault() {
+        let master_key = gen_master_key(SecStr::from("Correct Horse Battery Staple"), "Clarke Griffin").unwrap();
+        let mut vault = DecryptedVault::new(gen_entries_key(&master_key), gen_outer_key(&master_key));
+        vault.put_entry("twitter", &example_entry(), &mut EntryMetadata::default()).unwrap();
+        let mut storage = Vec::new();
+        vault.save(&mut storage).unwrap();
+        let mut wrapper: EncryptedVault = serde_cbor::from_slice(&storage).unwrap();
+        assert_eq!(wrapper.version, CURRENT_VAULT_VERSION);
+        wrapper.ciphertext[0] ^= 1;
+        let tampered = serde_cbor::to_vec(&wrapper).unwrap();
+        match DecryptedVault::open(gen_entries_key(&master_key), gen_outer_key(&master_key), &tampered[..]) {
+            Err(Error::OuterAuthenticationFailed) => (),
+            _ => panic!("Tampered vault was not rejected"),
+        }
+        wrapper.ciphertext[0] ^= 1;
+        wrapper.ciphertext.pop();
+        let truncated = serde_cbor::to_vec(&wrapper).unwrap();
+        match DecryptedVault::open(gen_entries_key(&master_key), gen_outer_key(&master_key), &truncated[..]) {
+            Err(Error::OuterAuthenticationFailed) => (),
+            _ => panic!("Truncated vault was not rejected"),
+        }
+    }
+
+    #[test]
+    fn test_upgrade_version_0_vault() {
+        let master_key = gen_master_key(SecStr::from("Correct Horse Battery Staple"), "Clarke Griffin").unwrap();
+        let outer_key = gen_outer_key(&master_key);
+        let mut vault = DecryptedVault::new(gen_entries_key(&master_key), outer_key.clone());
+        vault.put_entry("twitter", &example_entry(), &mut EntryMetadata::default()).unwrap();
+        let plaintext = serde_cbor::to_vec(&vault.data).unwrap();
+        let iv = vec![42; 16];
+        let mut ciphertext = vec![0; plaintext.len()];
+        aes::ctr(aes::KeySize::KeySize128, outer_key.unsecure(), &iv).process(&plaintext, &mut ciphertext);
+        let old_storage = serde_cbor::to_vec(&EncryptedVault { version: 0, nonce: iv, ciphertext: ciphertext, padding_policy: None }).unwrap();
+        let mut loaded_vault = DecryptedVault::open(gen_entries_key(&master_key), outer_key.clone(), &old_storage[..]).unwrap();
+        assert!(loaded_vault.get_entry("twitter").unwrap().0 == example_entry());
+        let mut new_storage = Vec::new();
+        loaded_vault.save(&mut new_storage).unwrap();
+        let wrapper: EncryptedVault = serde_cbor::from_slice(&new_storage).unwrap();
+        assert_eq!(wrapper.version, CURRENT_VAULT_VERSION);
+        let reloaded_vault = DecryptedVault::open(gen_entries_key(&master_key), outer_key, &new_storage[..]).unwrap();
+        assert!(reloaded_vault.get_entry("twitter").unwrap().0 == example_entry());
+    }
 }
@@ -6,6 +6,7 @@ extern crate serde_bytes;
 extern crate serde_cbor;
 extern crate serde;
 extern crate base64;
+extern crate base32;
 extern crate byteorder;
 #[cfg(all(unix, not(target_os = "android"), not(target_os = "ios")))]
 extern crate unix_socket;
@@ -31,6 +32,16 @@ pub mod attachments;
 pub mod vault;
 pub mod encvault;
 pub mod output;
+#[cfg(all(unix, not(target_os = "android"), not(target_os = "ios")))]
+pub mod agent;
+pub mod otp;
+pub mod sqrl;
+pub mod minisign;
+pub mod age;
+pub mod share;
+pub mod openpgp;
+pub mod wireguard;
+pub mod bip39;
 pub mod merge;
 pub mod import;
 
@@ -1,46 +1,653 @@
+//! Merging vaults (e.g. two copies of the same file after a sync conflict).
+//!
+//! Entries are merged field by field, using the `FieldVersion`s from the entry metadata:
+//! a change wins if it was made on top of the other side's version of the field.
+//! Changes made to the same field independently are conflicts, left for the user to resolve.
+//!
+//! Entries are matched by their IDs, not by their names, so renames are merged too.
+//! Entries removed after their last change on the other side are not brought back (see `Vault::tombstone`).
+
+use std::collections::btree_map::BTreeMap;
+use std::collections::btree_set::BTreeSet;
+use chrono::{DateTime, UTC};
 use vault::{Vault, WritableVault};
+use data::*;
+use result::{Error, Result};
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct MergeConflict {
+    pub entry_name: String,
+    pub field_name: String,
+    /// `None` means the field was removed
+    pub ours: Option<Field>,
+    pub theirs: Option<Field>,
+    pub ours_version: FieldVersion,
+    pub theirs_version: FieldVersion,
+}
 
+#[derive(PartialEq, Clone, Debug)]
 pub enum MergeLogEntry {
     Added(String),
-    IsNewer(String),
-    IsOlder(String),
+    Updated(String),
+    Unchanged(String),
+    /// The entry was renamed in the other vault (old name, new name)
+    Renamed(String, String),
+    /// Different entries (not copies of the same one) have the same name, the other one wasn't merged
+    NameCollision(String),
+    /// Removed in the other vault after our last change
+    Removed(String),
+    /// Removed in our vault after the other vault's last change
+    NotRestored(String),
+    /// The entry was merged, keeping our value of this field
+    Conflict(MergeConflict),
     WeirdError(String),
 }
 
-pub fn merge_vaults<I: ?Sized, F: ?Sized>(into_vault: &mut I, from_vault: &F) -> Vec<MergeLogEntry>
-    where I: Vault + WritableVault,
+/// The result of merging one entry.
+pub struct EntryMerge {
+    pub entry: Entry,
+    pub metadata: EntryMetadata,
+    /// Whether the entry (or its tags) differs from ours
+    pub changed: bool,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+/// Fields written before field versions existed are treated as changed when the entry was.
+fn field_version(entry: &Entry, metadata: &EntryMetadata, field_name: &str) -> Option<FieldVersion> {
+    metadata.field_versions.get(field_name).cloned().or_else(|| if entry.fields.contains_key(field_name) {
+        Some(FieldVersion { updated_at: metadata.updated_at, based_on: None })
+    } else {
+        None
+    })
+}
+
+fn is_based_on(version: &FieldVersion, other: &FieldVersion) -> bool {
+    version.based_on.map(|b| b >= other.updated_at).unwrap_or(false)
+}
+
+pub fn merge_entries(entry_name: &str, ours: (&Entry, &EntryMetadata), theirs: (&Entry, &EntryMetadata)) -> EntryMerge {
+    let (our_entry, our_meta) = ours;
+    let (their_entry, their_meta) = theirs;
+    let mut entry = our_entry.clone();
+    let mut metadata = our_meta.clone();
+    let mut conflicts = Vec::new();
+    let field_names = our_entry.fields.keys().chain(their_entry.fields.keys())
+        .chain(our_meta.field_versions.keys()).chain(their_meta.field_versions.keys())
+        .cloned().collect::<BTreeSet<_>>();
+    for field_name in field_names {
+        let our_field = our_entry.fields.get(&field_name);
+        let their_field = their_entry.fields.get(&field_name);
+        let (our_version, their_version) = match (field_version(our_entry, our_meta, &field_name), field_version(their_entry, their_meta, &field_name)) {
+            (_, None) => continue,
+            (None, Some(t)) => {
+                take_field(&mut entry, &mut metadata, &field_name, their_field, t);
+                continue;
+            },
+            (Some(o), Some(t)) => (o, t),
+        };
+        // Unversioned fields would otherwise get the merged entry's time
+        if our_field.is_some() {
+            metadata.field_versions.entry(field_name.clone()).or_insert(our_version);
+        }
+        if our_field == their_field {
+            if their_version.updated_at > our_version.updated_at {
+                metadata.field_versions.insert(field_name, their_version);
+            }
+        } else if is_based_on(&their_version, &our_version) {
+            take_field(&mut entry, &mut metadata, &field_name, their_field, their_version);
+        } else if !is_based_on(&our_version, &their_version) {
+            conflicts.push(MergeConflict {
+                entry_name: entry_name.to_owned(),
+                field_name: field_name.to_owned(),
+                ours: our_field.cloned(),
+                theirs: their_field.cloned(),
+                ours_version: our_version,
+                theirs_version: their_version,
+            });
+        }
+    }
+    for tag in their_meta.tags.iter() {
+        if !metadata.tags.contains(tag) {
+            metadata.tags.push(tag.to_owned());
+        }
+    }
+    if their_meta.created_at < metadata.created_at {
+        metadata.created_at = their_meta.created_at;
+    }
+    if their_meta.updated_at > metadata.updated_at {
+        metadata.updated_at = their_meta.updated_at;
+    }
+    let changed = entry != *our_entry || metadata.tags != our_meta.tags;
+    EntryMerge { entry: entry, metadata: metadata, changed: changed, conflicts: conflicts }
+}
+
+fn take_field(entry: &mut Entry, metadata: &mut EntryMetadata, field_name: &str, field: Option<&Field>, version: FieldVersion) {
+    match field {
+        Some(field) => entry.fields.insert(field_name.to_owned(), field.clone()),
+        None => entry.fields.remove(field_name),
+    };
+    metadata.field_versions.insert(field_name.to_owned(), version);
+}
+
+/// What merging does with an entry.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum MergeAction {
+    /// Copied from the other vault
+    Add,
+    /// Changed with changes from the other vault
+    Update,
+    /// Nothing to take from the other vault (except maybe the name), or only conflicting changes
+    Keep,
+    /// Removed in the other vault after our last change
+    Remove,
+    /// Removed in our vault after the other vault's last change
+    NotRestore,
+    /// A different entry (not a copy of this one) has the same name, the other vault's entry isn't merged
+    NameCollision,
+    /// The entry couldn't be read
+    Error,
+}
+
+/// One entry of a `MergePlan`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct MergePlanEntry {
+    pub entry_name: String,
+    /// Our name of the entry, if it was renamed in the other vault
+    pub renamed_from: Option<String>,
+    pub action: MergeAction,
+    /// When the entry was last changed in our vault (or removed, for `NotRestore`)
+    pub ours_updated_at: Option<DateTime<UTC>>,
+    /// When the entry was last changed in the other vault (or removed, for `Remove`)
+    pub theirs_updated_at: Option<DateTime<UTC>>,
+    /// Conflicting fields, our values are kept
+    pub conflicts: Vec<MergeConflict>,
+    merged: Option<(Entry, EntryMetadata)>,
+}
+
+impl MergePlanEntry {
+    fn new(entry_name: &str, action: MergeAction) -> MergePlanEntry {
+        MergePlanEntry {
+            entry_name: entry_name.to_owned(),
+            renamed_from: None,
+            action: action,
+            ours_updated_at: None,
+            theirs_updated_at: None,
+            conflicts: Vec::new(),
+            merged: None,
+        }
+    }
+}
+
+/// What `apply_merge` would do, without changing anything.
+#[derive(PartialEq, Clone, Debug)]
+pub struct MergePlan {
+    pub entries: Vec<MergePlanEntry>,
+}
+
+/// Works out how to merge `from_vault` into `into_vault` (a dry run).
+pub fn plan_merge<I: ?Sized, F: ?Sized>(into_vault: &I, from_vault: &F) -> MergePlan
+    where I: Vault,
           F: Vault
 {
-    let mut results = Vec::with_capacity(from_vault.len());
-    for entry_name in from_vault.entry_names() {
-        if let Ok((from_entry, from_entry_meta)) = from_vault.get_entry(entry_name) {
-            // After changing the into_vault type to be generic, the borrow checker started
-            // complaining about multiple borrows o_0 So we use a raw pointer here.
-            // Probably this is related: https://github.com/rust-lang/rfcs/issues/811
-            if let Some(_) = unsafe {
-                   (*(into_vault as *const I)).entry_names().find(|&n| {
-                        n == entry_name
-                    })
-               } {
-                if let Ok((_, into_entry_meta)) = into_vault.get_entry(entry_name) {
-                    if from_entry_meta.updated_at > into_entry_meta.updated_at {
-                        results.push(MergeLogEntry::IsNewer(entry_name.to_owned()));
-                    } else {
-                        results.push(MergeLogEntry::IsOlder(entry_name.to_owned()));
-                    }
+    let mut entries = Vec::with_capacity(from_vault.len());
+    let mut into_names_by_id = BTreeMap::new();
+    let mut into_metas = Vec::with_capacity(into_vault.len());
+    for entry_name in into_vault.entry_names() {
+        if let Ok((_, meta)) = into_vault.get_entry(entry_name) {
+            into_names_by_id.insert(meta.id.clone(), entry_name.to_owned());
+            into_metas.push((entry_name.to_owned(), meta));
+        }
+    }
+    // The names the entries will have after merging
+    let mut taken_names = into_vault.entry_names().cloned().collect::<BTreeSet<_>>();
+    let mut from_ids = BTreeSet::new();
+    for from_name in from_vault.entry_names() {
+        let (from_entry, from_entry_meta) = match from_vault.get_entry(from_name) {
+            Ok(x) => x,
+            Err(_) => {
+                entries.push(MergePlanEntry::new(from_name, MergeAction::Error));
+                continue;
+            },
+        };
+        from_ids.insert(from_entry_meta.id.clone());
+        let into_name = match into_names_by_id.get(&from_entry_meta.id) {
+            Some(into_name) => into_name.to_owned(),
+            None => {
+                let mut plan_entry = MergePlanEntry::new(from_name, MergeAction::Add);
+                plan_entry.theirs_updated_at = Some(from_entry_meta.updated_at);
+                let deleted_at = match into_vault.tombstone(from_name) {
+                    Some(ref t) if t.is_for(&from_entry_meta.id) && t.deleted_at >= from_entry_meta.updated_at => Some(t.deleted_at),
+                    _ => None,
+                };
+                if taken_names.contains(from_name) {
+                    plan_entry.action = MergeAction::NameCollision;
+                } else if deleted_at.is_some() {
+                    plan_entry.action = MergeAction::NotRestore;
+                    plan_entry.ours_updated_at = deleted_at;
                 } else {
-                    results.push(MergeLogEntry::WeirdError(entry_name.to_owned()));
+                    taken_names.insert(from_name.to_owned());
+                    plan_entry.merged = Some((from_entry, from_entry_meta));
                 }
+                entries.push(plan_entry);
+                continue;
+            },
+        };
+        let (into_entry, into_entry_meta) = match into_vault.get_entry(&into_name) {
+            Ok(x) => x,
+            Err(_) => {
+                entries.push(MergePlanEntry::new(&into_name, MergeAction::Error));
+                continue;
+            },
+        };
+        // The most recent rename wins
+        let mut entry_name = into_name.clone();
+        if *from_name != into_name && from_entry_meta.renamed_at > into_entry_meta.renamed_at {
+            if taken_names.contains(from_name) {
+                entries.push(MergePlanEntry::new(from_name, MergeAction::NameCollision));
             } else {
-                if let Ok(_) = into_vault.put_entry(entry_name, &from_entry, &mut from_entry_meta.clone()) {
-                    results.push(MergeLogEntry::Added(entry_name.to_owned()));
+                taken_names.remove(&into_name);
+                taken_names.insert(from_name.to_owned());
+                entry_name = from_name.to_owned();
+            }
+        }
+        let mut merge = merge_entries(&entry_name, (&into_entry, &into_entry_meta), (&from_entry, &from_entry_meta));
+        let mut plan_entry = MergePlanEntry::new(&entry_name, if merge.changed { MergeAction::Update } else { MergeAction::Keep });
+        plan_entry.ours_updated_at = Some(into_entry_meta.updated_at);
+        plan_entry.theirs_updated_at = Some(from_entry_meta.updated_at);
+        if entry_name != into_name {
+            merge.metadata.renamed_at = from_entry_meta.renamed_at;
+            plan_entry.renamed_from = Some(into_name);
+        }
+        if plan_entry.renamed_from.is_some() || merge.changed || merge.metadata.field_versions != into_entry_meta.field_versions {
+            plan_entry.merged = Some((merge.entry, merge.metadata));
+        }
+        plan_entry.conflicts = merge.conflicts;
+        entries.push(plan_entry);
+    }
+    for (entry_name, meta) in into_metas {
+        // Entries that exist in the other vault were only renamed there, if anything
+        if from_ids.contains(&meta.id) {
+            continue;
+        }
+        if let Some(tombstone) = from_vault.tombstone(&entry_name) {
+            if tombstone.is_for(&meta.id) && tombstone.deleted_at > meta.updated_at {
+                let mut plan_entry = MergePlanEntry::new(&entry_name, MergeAction::Remove);
+                plan_entry.ours_updated_at = Some(meta.updated_at);
+                plan_entry.theirs_updated_at = Some(tombstone.deleted_at);
+                entries.push(plan_entry);
+            }
+        }
+    }
+    MergePlan { entries: entries }
+}
+
+/// Carries out a plan made by `plan_merge` (for the same vaults).
+/// Conflicts are kept as they are and logged, see `resolve_conflict`.
+pub fn apply_merge<I: ?Sized>(into_vault: &mut I, plan: MergePlan) -> Vec<MergeLogEntry>
+    where I: Vault + WritableVault
+{
+    let mut results = Vec::with_capacity(plan.entries.len());
+    for plan_entry in plan.entries {
+        let entry_name = plan_entry.entry_name;
+        match plan_entry.action {
+            MergeAction::Add => {
+                let (entry, metadata) = plan_entry.merged.expect("Planned addition without an entry");
+                if let Ok(_) = into_vault.put_merged_entry(&entry_name, &entry, &metadata) {
+                    results.push(MergeLogEntry::Added(entry_name));
                 } else {
-                    results.push(MergeLogEntry::WeirdError(entry_name.to_owned()));
+                    results.push(MergeLogEntry::WeirdError(entry_name));
                 }
-            }
-        } else {
-            results.push(MergeLogEntry::WeirdError(entry_name.to_owned()));
+            },
+            MergeAction::Update | MergeAction::Keep => {
+                if let Some((entry, metadata)) = plan_entry.merged {
+                    if let Some(ref old_name) = plan_entry.renamed_from {
+                        into_vault.remove_entry(old_name);
+                    }
+                    if let Err(_) = into_vault.put_merged_entry(&entry_name, &entry, &metadata) {
+                        results.push(MergeLogEntry::WeirdError(entry_name));
+                        continue;
+                    }
+                }
+                if let Some(old_name) = plan_entry.renamed_from {
+                    results.push(MergeLogEntry::Renamed(old_name, entry_name.clone()));
+                }
+                results.push(if plan_entry.action == MergeAction::Update {
+                    MergeLogEntry::Updated(entry_name)
+                } else {
+                    MergeLogEntry::Unchanged(entry_name)
+                });
+                results.extend(plan_entry.conflicts.into_iter().map(MergeLogEntry::Conflict));
+            },
+            MergeAction::Remove => {
+                let deleted_at = plan_entry.theirs_updated_at.expect("Planned removal without a tombstone");
+                into_vault.remove_merged_entry(&entry_name, deleted_at);
+                results.push(MergeLogEntry::Removed(entry_name));
+            },
+            MergeAction::NotRestore => results.push(MergeLogEntry::NotRestored(entry_name)),
+            MergeAction::NameCollision => results.push(MergeLogEntry::NameCollision(entry_name)),
+            MergeAction::Error => results.push(MergeLogEntry::WeirdError(entry_name)),
         }
     }
     results
 }
+
+pub fn merge_vaults<I: ?Sized, F: ?Sized>(into_vault: &mut I, from_vault: &F) -> Vec<MergeLogEntry>
+    where I: Vault + WritableVault,
+          F: Vault
+{
+    let plan = plan_merge(&*into_vault, from_vault);
+    apply_merge(into_vault, plan)
+}
+
+/// How to resolve conflicts without asking.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ConflictPreference {
+    /// The value that was changed last
+    Newer,
+    Ours,
+    Theirs,
+}
+
+impl ConflictPreference {
+    pub fn takes_theirs(&self, conflict: &MergeConflict) -> bool {
+        match *self {
+            ConflictPreference::Newer => conflict.theirs_version.updated_at > conflict.ours_version.updated_at,
+            ConflictPreference::Ours => false,
+            ConflictPreference::Theirs => true,
+        }
+    }
+}
+
+/// Resolves a conflict reported by `merge_vaults` with our or their value of the field.
+/// Fails if the field has changed since the merge.
+/// The new version is based on both conflicting versions, so it wins in future merges with either copy.
+pub fn resolve_conflict<I: ?Sized>(into_vault: &mut I, conflict: &MergeConflict, take_theirs: bool) -> Result<()>
+    where I: Vault + WritableVault
+{
+    let (mut entry, mut metadata) = into_vault.get_entry(&conflict.entry_name)?;
+    if entry.fields.get(&conflict.field_name) != conflict.ours.as_ref() {
+        return Err(Error::MergeConflictOutdated);
+    }
+    let field = if take_theirs { conflict.theirs.as_ref() } else { conflict.ours.as_ref() };
+    let based_on = if conflict.theirs_version.updated_at > conflict.ours_version.updated_at {
+        conflict.theirs_version.updated_at
+    } else {
+        conflict.ours_version.updated_at
+    };
+    take_field(&mut entry, &mut metadata, &conflict.field_name, field, FieldVersion { updated_at: UTC::now(), based_on: Some(based_on) });
+    into_vault.put_entry(&conflict.entry_name, &entry, &mut metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secstr::SecStr;
+    use encvault::DecryptedVault;
+
+    fn new_vault() -> DecryptedVault {
+        DecryptedVault::new(SecStr::new(vec![1; 64]), SecStr::new(vec![2; 16]))
+    }
+
+    fn copy_vault(vault: &mut DecryptedVault) -> DecryptedVault {
+        let mut storage = Vec::new();
+        vault.save(&mut storage).unwrap();
+        DecryptedVault::open(SecStr::new(vec![1; 64]), SecStr::new(vec![2; 16]), &storage[..]).unwrap()
+    }
+
+    fn stored(text: &str) -> Field {
+        Field::Stored { data: SecStr::from(text), usage: StoredUsage::Text }
+    }
+
+    fn set_field(vault: &mut DecryptedVault, field_name: &str, field: Option<Field>) {
+        let (mut entry, mut metadata) = vault.get_entry("example.com").unwrap_or((Entry::default(), EntryMetadata::default()));
+        match field {
+            Some(field) => entry.fields.insert(field_name.to_owned(), field),
+            None => entry.fields.remove(field_name),
+        };
+        vault.put_entry("example.com", &entry, &mut metadata).unwrap();
+    }
+
+    fn get_field(vault: &DecryptedVault, field_name: &str) -> Option<Field> {
+        vault.get_entry("example.com").unwrap().0.fields.get(field_name).cloned()
+    }
+
+    #[test]
+    fn test_merge_added() {
+        let mut ours = new_vault();
+        let mut theirs = new_vault();
+        set_field(&mut theirs, "password", Some(stored("one")));
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Added("example.com".to_owned())]);
+        assert_eq!(get_field(&ours, "password"), Some(stored("one")));
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Unchanged("example.com".to_owned())]);
+    }
+
+    #[test]
+    fn test_merge_different_fields() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        set_field(&mut ours, "username", Some(stored("me")));
+        let mut theirs = copy_vault(&mut ours);
+        set_field(&mut ours, "password", Some(stored("two")));
+        set_field(&mut theirs, "username", Some(stored("myself")));
+        set_field(&mut theirs, "note", Some(stored("hi")));
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Updated("example.com".to_owned())]);
+        assert_eq!(get_field(&ours, "password"), Some(stored("two")));
+        assert_eq!(get_field(&ours, "username"), Some(stored("myself")));
+        assert_eq!(get_field(&ours, "note"), Some(stored("hi")));
+        // Merging back gives them the same entry
+        assert_eq!(merge_vaults(&mut theirs, &ours), vec![MergeLogEntry::Updated("example.com".to_owned())]);
+        assert_eq!(theirs.get_entry("example.com").unwrap().0, ours.get_entry("example.com").unwrap().0);
+    }
+
+    #[test]
+    fn test_merge_sequential_changes() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        let mut theirs = copy_vault(&mut ours);
+        set_field(&mut theirs, "password", Some(stored("two")));
+        let mut newest = copy_vault(&mut theirs);
+        set_field(&mut newest, "password", Some(stored("three")));
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Updated("example.com".to_owned())]);
+        assert_eq!(get_field(&ours, "password"), Some(stored("two")));
+        assert_eq!(merge_vaults(&mut ours, &newest), vec![MergeLogEntry::Updated("example.com".to_owned())]);
+        assert_eq!(get_field(&ours, "password"), Some(stored("three")));
+        // Older versions don't win
+        assert_eq!(merge_vaults(&mut newest, &theirs), vec![MergeLogEntry::Unchanged("example.com".to_owned())]);
+        assert_eq!(get_field(&newest, "password"), Some(stored("three")));
+    }
+
+    #[test]
+    fn test_merge_removed_field() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        set_field(&mut ours, "old_password", Some(stored("zero")));
+        let mut theirs = copy_vault(&mut ours);
+        set_field(&mut theirs, "old_password", None);
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Updated("example.com".to_owned())]);
+        assert_eq!(get_field(&ours, "old_password"), None);
+        assert_eq!(get_field(&ours, "password"), Some(stored("one")));
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        let mut theirs = copy_vault(&mut ours);
+        set_field(&mut ours, "password", Some(stored("two")));
+        set_field(&mut theirs, "password", Some(stored("three")));
+        let log = merge_vaults(&mut ours, &theirs);
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0], MergeLogEntry::Unchanged("example.com".to_owned()));
+        let conflict = match log[1] {
+            MergeLogEntry::Conflict(ref c) => c.clone(),
+            ref l => panic!("Unexpected log entry: {:?}", l),
+        };
+        assert_eq!(conflict.field_name, "password");
+        assert_eq!(conflict.ours, Some(stored("two")));
+        assert_eq!(conflict.theirs, Some(stored("three")));
+        assert_eq!(get_field(&ours, "password"), Some(stored("two")));
+        resolve_conflict(&mut ours, &conflict, true).unwrap();
+        assert_eq!(get_field(&ours, "password"), Some(stored("three")));
+        // The resolution wins over both sides
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Unchanged("example.com".to_owned())]);
+        assert_eq!(merge_vaults(&mut theirs, &ours), vec![MergeLogEntry::Unchanged("example.com".to_owned())]);
+        let mut other = copy_vault(&mut ours);
+        match resolve_conflict(&mut other, &conflict, false) {
+            Err(Error::MergeConflictOutdated) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_merge_removed_entry() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        let mut theirs = copy_vault(&mut ours);
+        ours.remove_entry("example.com");
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::NotRestored("example.com".to_owned())]);
+        assert!(ours.get_entry("example.com").is_err());
+        assert_eq!(merge_vaults(&mut theirs, &ours), vec![MergeLogEntry::Removed("example.com".to_owned())]);
+        assert!(theirs.get_entry("example.com").is_err());
+        assert!(theirs.tombstone("example.com").is_some());
+    }
+
+    #[test]
+    fn test_merge_changed_after_removal() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        let mut theirs = copy_vault(&mut ours);
+        ours.remove_entry("example.com");
+        set_field(&mut theirs, "password", Some(stored("two")));
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Added("example.com".to_owned())]);
+        assert_eq!(get_field(&ours, "password"), Some(stored("two")));
+        assert!(ours.tombstone("example.com").is_none());
+    }
+
+    #[test]
+    fn test_merge_keeps_times() {
+        let mut ours = new_vault();
+        let mut theirs = new_vault();
+        set_field(&mut theirs, "password", Some(stored("one")));
+        let mut removed = copy_vault(&mut theirs);
+        removed.remove_entry("example.com");
+        let deleted_at = removed.tombstone("example.com").unwrap().deleted_at;
+        let mut changed = copy_vault(&mut theirs);
+        set_field(&mut changed, "password", Some(stored("two")));
+        // Adding the entry isn't a change that would make it newer than the removal
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Added("example.com".to_owned())]);
+        assert_eq!(ours.get_entry("example.com").unwrap().1.updated_at, theirs.get_entry("example.com").unwrap().1.updated_at);
+        assert_eq!(merge_vaults(&mut ours, &removed), vec![MergeLogEntry::Removed("example.com".to_owned())]);
+        // Removing it isn't newer than the change made after the removal either
+        assert_eq!(ours.tombstone("example.com").unwrap().deleted_at, deleted_at);
+        assert_eq!(merge_vaults(&mut ours, &changed), vec![MergeLogEntry::Added("example.com".to_owned())]);
+        assert_eq!(get_field(&ours, "password"), Some(stored("two")));
+    }
+
+    #[test]
+    fn test_merge_three_copies() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        let mut theirs = copy_vault(&mut ours);
+        let mut removed = copy_vault(&mut ours);
+        set_field(&mut ours, "username", Some(stored("me")));
+        removed.remove_entry("example.com");
+        // Our change was made before the removal, merging it later doesn't bring the entry back
+        assert_eq!(merge_vaults(&mut theirs, &ours), vec![MergeLogEntry::Updated("example.com".to_owned())]);
+        assert_eq!(merge_vaults(&mut theirs, &removed), vec![MergeLogEntry::Removed("example.com".to_owned())]);
+        assert!(theirs.get_entry("example.com").is_err());
+        assert_eq!(merge_vaults(&mut removed, &ours), vec![MergeLogEntry::NotRestored("example.com".to_owned())]);
+    }
+
+    fn rename(vault: &mut DecryptedVault, old_name: &str, new_name: &str) {
+        let (entry, mut metadata) = vault.get_entry(old_name).unwrap();
+        vault.rename_entry(old_name, new_name, &entry, &mut metadata).unwrap();
+    }
+
+    #[test]
+    fn test_merge_renamed_entry() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        let mut theirs = copy_vault(&mut ours);
+        set_field(&mut ours, "password", Some(stored("two")));
+        rename(&mut theirs, "example.com", "example.org");
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![
+            MergeLogEntry::Renamed("example.com".to_owned(), "example.org".to_owned()),
+            MergeLogEntry::Unchanged("example.org".to_owned()),
+        ]);
+        assert!(ours.get_entry("example.com").is_err());
+        assert_eq!(ours.get_entry("example.org").unwrap().0.fields.get("password"), Some(&stored("two")));
+        // The older name doesn't come back
+        assert_eq!(merge_vaults(&mut theirs, &ours), vec![MergeLogEntry::Updated("example.org".to_owned())]);
+        assert!(theirs.get_entry("example.com").is_err());
+        assert_eq!(theirs.get_entry("example.org").unwrap().0.fields.get("password"), Some(&stored("two")));
+    }
+
+    #[test]
+    fn test_merge_name_collision() {
+        let mut ours = new_vault();
+        let mut theirs = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        set_field(&mut theirs, "password", Some(stored("two")));
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::NameCollision("example.com".to_owned())]);
+        assert_eq!(get_field(&ours, "password"), Some(stored("one")));
+        rename(&mut theirs, "example.com", "example.org");
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Added("example.org".to_owned())]);
+    }
+
+    #[test]
+    fn test_plan_merge() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        let mut theirs = copy_vault(&mut ours);
+        set_field(&mut ours, "password", Some(stored("two")));
+        set_field(&mut theirs, "password", Some(stored("three")));
+        let plan = plan_merge(&ours, &theirs);
+        assert_eq!(get_field(&ours, "password"), Some(stored("two")));
+        assert_eq!(plan.entries.len(), 1);
+        assert_eq!(plan.entries[0].entry_name, "example.com");
+        assert_eq!(plan.entries[0].action, MergeAction::Keep);
+        assert_eq!(plan.entries[0].ours_updated_at, Some(ours.get_entry("example.com").unwrap().1.updated_at));
+        assert_eq!(plan.entries[0].theirs_updated_at, Some(theirs.get_entry("example.com").unwrap().1.updated_at));
+        let conflict = plan.entries[0].conflicts[0].clone();
+        assert!(ConflictPreference::Theirs.takes_theirs(&conflict));
+        assert!(!ConflictPreference::Ours.takes_theirs(&conflict));
+        assert!(ConflictPreference::Newer.takes_theirs(&conflict));
+        assert_eq!(apply_merge(&mut ours, plan), vec![
+            MergeLogEntry::Unchanged("example.com".to_owned()),
+            MergeLogEntry::Conflict(conflict),
+        ]);
+    }
+
+    #[test]
+    fn test_merge_legacy_entries() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        let mut theirs = copy_vault(&mut ours);
+        for vault in vec![&mut ours, &mut theirs] {
+            for ee in vault.data.entries.values_mut() {
+                ee.metadata.field_versions.clear();
+            }
+        }
+        set_field(&mut theirs, "password", Some(stored("two")));
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Updated("example.com".to_owned())]);
+        assert_eq!(get_field(&ours, "password"), Some(stored("two")));
+    }
+
+    #[test]
+    fn test_merge_legacy_entries_different_fields() {
+        let mut ours = new_vault();
+        set_field(&mut ours, "password", Some(stored("one")));
+        set_field(&mut ours, "username", Some(stored("clarke")));
+        let mut theirs = copy_vault(&mut ours);
+        for vault in vec![&mut ours, &mut theirs] {
+            for ee in vault.data.entries.values_mut() {
+                ee.metadata.field_versions.clear();
+            }
+        }
+        set_field(&mut ours, "username", Some(stored("griffin")));
+        set_field(&mut theirs, "password", Some(stored("two")));
+        assert_eq!(merge_vaults(&mut ours, &theirs), vec![MergeLogEntry::Updated("example.com".to_owned())]);
+        assert_eq!(get_field(&ours, "password"), Some(stored("two")));
+        assert_eq!(get_field(&ours, "username"), Some(stored("griffin")));
+    }
+}
@@ -0,0 +1,207 @@
+use base64;
+use byteorder::{ByteOrder, LittleEndian};
+use sodiumoxide::crypto::sign::ed25519;
+use data::Ed25519Usage;
+use output::Output;
+use result::{Error, Result};
+use util::blake2b;
+
+/// Like the signify keynum, derived from the public key instead of being random.
+pub fn minisign_key_id(pubkey_bytes: &[u8]) -> Vec<u8> {
+    blake2b(pubkey_bytes, b"freepass.minisign", 8)
+}
+
+/// The key ID as shown by minisign (a little endian number in hex).
+pub fn minisign_key_id_hex(key_id: &[u8]) -> String {
+    format!("{:016X}", LittleEndian::read_u64(key_id))
+}
+
+/// The trusted comment minisign itself puts into signatures.
+pub fn minisign_default_trusted_comment(timestamp: u64, file_name: &str, prehashed: bool) -> String {
+    format!("timestamp:{}\tfile:{}{}", timestamp, file_name, if prehashed { "\thashed" } else { "" })
+}
+
+/// A minisign public key, either derived or read from a `.pub` file.
+#[derive(PartialEq, Clone, Debug)]
+pub struct MinisignPublicKey {
+    pub key_id: Vec<u8>,
+    pub key: ed25519::PublicKey,
+}
+
+impl MinisignPublicKey {
+    pub fn from_keypair(keypair: &Output) -> Result<MinisignPublicKey> {
+        if let &Output::Ed25519Keypair(Ed25519Usage::Minisign, pubkey, _) = keypair {
+            let ed25519::PublicKey(pubkey_bytes) = pubkey;
+            Ok(MinisignPublicKey { key_id: minisign_key_id(&pubkey_bytes), key: pubkey })
+        } else {
+            Err(Error::InappropriateFormat)
+        }
+    }
+
+    /// Parses a `.pub` file, or just its base64 line (what `minisign -P` takes).
+    pub fn parse(text: &str) -> Result<MinisignPublicKey> {
+        let line = text.lines()
+            .filter(|l| !l.starts_with("untrusted comment:") && l.trim().len() > 0)
+            .next()
+            .ok_or(Error::InvalidMinisignFormat)?;
+        let raw = base64::decode(line.trim()).map_err(|_| Error::InvalidMinisignFormat)?;
+        if raw.len() != 10 + ed25519::PUBLICKEYBYTES || &raw[0..2] != b"Ed" {
+            return Err(Error::InvalidMinisignFormat);
+        }
+        Ok(MinisignPublicKey {
+            key_id: raw[2..10].to_vec(),
+            key: ed25519::PublicKey::from_slice(&raw[10..]).ok_or(Error::InvalidMinisignFormat)?,
+        })
+    }
+
+    pub fn to_file(&self) -> String {
+        let ed25519::PublicKey(pubkey_bytes) = self.key;
+        let mut raw = vec![];
+        raw.extend(b"Ed");
+        raw.extend(&self.key_id);
+        raw.extend(&pubkey_bytes);
+        format!("untrusted comment: minisign public key {}\n{}\n", minisign_key_id_hex(&self.key_id), base64::encode(&raw))
+    }
+}
+
+pub fn minisign_public_key_output(keypair: &Output) -> Result<String> {
+    Ok(MinisignPublicKey::from_keypair(keypair)?.to_file())
+}
+
+fn prehash(data: &[u8]) -> Vec<u8> {
+    blake2b(b"", data, 64)
+}
+
+/// Signs the data, either directly (legacy) or its BLAKE2b-512 hash (prehashed, for big files).
+/// The trusted comment is signed too, together with the signature.
+pub fn minisign_sign(keypair: &Output, untrusted_comment: &str, trusted_comment: &str, data: &[u8], prehashed: bool) -> Result<String> {
+    if let &Output::Ed25519Keypair(Ed25519Usage::Minisign, ed25519::PublicKey(pubkey_bytes), ref seckey) = keypair {
+        if untrusted_comment.contains('\n') || trusted_comment.contains('\n') {
+            return Err(Error::InvalidMinisignFormat);
+        }
+        let ed25519::Signature(sig_bytes) = if prehashed {
+            ed25519::sign_detached(&prehash(data), seckey)
+        } else {
+            ed25519::sign_detached(data, seckey)
+        };
+        let mut raw = vec![];
+        raw.extend(if prehashed { b"ED" } else { b"Ed" });
+        raw.extend(&minisign_key_id(&pubkey_bytes));
+        raw.extend(sig_bytes.iter());
+        let mut global_data = sig_bytes.to_vec();
+        global_data.extend(trusted_comment.as_bytes());
+        let ed25519::Signature(global_sig_bytes) = ed25519::sign_detached(&global_data, seckey);
+        Ok(format!(
+            "untrusted comment: {}\n{}\ntrusted comment: {}\n{}\n",
+            untrusted_comment,
+            base64::encode(&raw),
+            trusted_comment,
+            base64::encode(&global_sig_bytes[..])
+        ))
+    } else {
+        Err(Error::InappropriateFormat)
+    }
+}
+
+/// Verifies a `.minisig` signature of the data (both modes), returns the trusted comment.
+pub fn minisign_verify(pubkey: &MinisignPublicKey, signature: &str, data: &[u8]) -> Result<String> {
+    let lines = signature.lines().collect::<Vec<_>>();
+    if lines.len() != 4 || !lines[0].starts_with("untrusted comment:") || !lines[2].starts_with("trusted comment: ") {
+        return Err(Error::InvalidMinisignFormat);
+    }
+    let raw = base64::decode(lines[1].trim()).map_err(|_| Error::InvalidMinisignFormat)?;
+    let global_sig_bytes = base64::decode(lines[3].trim()).map_err(|_| Error::InvalidMinisignFormat)?;
+    if raw.len() != 10 + ed25519::SIGNATUREBYTES {
+        return Err(Error::InvalidMinisignFormat);
+    }
+    let prehashed = match &raw[0..2] {
+        b"Ed" => false,
+        b"ED" => true,
+        _ => return Err(Error::InvalidMinisignFormat),
+    };
+    if &raw[2..10] != &pubkey.key_id[..] {
+        return Err(Error::MinisignWrongKey);
+    }
+    let sig = ed25519::Signature::from_slice(&raw[10..]).ok_or(Error::InvalidMinisignFormat)?;
+    let global_sig = ed25519::Signature::from_slice(&global_sig_bytes).ok_or(Error::InvalidMinisignFormat)?;
+    let sig_ok = if prehashed {
+        ed25519::verify_detached(&sig, &prehash(data), &pubkey.key)
+    } else {
+        ed25519::verify_detached(&sig, data, &pubkey.key)
+    };
+    let trusted_comment = &lines[2]["trusted comment: ".len()..];
+    let mut global_data = raw[10..].to_vec();
+    global_data.extend(trusted_comment.as_bytes());
+    if !sig_ok || !ed25519::verify_detached(&global_sig, &global_data, &pubkey.key) {
+        return Err(Error::SignatureVerificationFailed);
+    }
+    Ok(trusted_comment.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair() -> Output {
+        let (pubkey, seckey) = ed25519::keypair_from_seed(&ed25519::Seed::from_slice(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap());
+        Output::Ed25519Keypair(Ed25519Usage::Minisign, pubkey, seckey)
+    }
+
+    const LEGACY_SIGNATURE: &'static str = concat!(
+        "untrusted comment: signature from freepass key 76E658F18A3289A2\n",
+        "RWSiiTKK8VjmdquI/gtU4NWNehjKQjhXsPp15mrjGTC1TfZ4SHESosQOOFkOpd+UPSUsoMac2pA5NEi+u5oHwLkKV9UeLm69JAI=\n",
+        "trusted comment: timestamp:1500000000\tfile:hello.txt\n",
+        "JC0/P51EWlLkh+iH6HvOQU+oeoYXn/FW3OeWgyG3RE2fGQjm2fK0+q1V9eqK3Bc79GsuHMgL7pHLHOB0r62oDg==\n"
+    );
+
+    const PREHASHED_SIGNATURE: &'static str = concat!(
+        "untrusted comment: signature from freepass key 76E658F18A3289A2\n",
+        "RUSiiTKK8VjmdiHwnLbJAOckZBCjlX0sppn+YDcfeQ0pXsEo+vm8xYX9tdr5zm4wmN9ST3MyOgSok8nauxXA2e/Epts+wVXvIQM=\n",
+        "trusted comment: timestamp:1500000000\tfile:hello.txt\thashed\n",
+        "k/m/XDmLAk327RaX2wrUkdeNq3g1VmqnzYginHN63yQQ6t/+jop4t8RlSA5Hwxl5cf+rfBKxDSUxQkDWGb/tCw==\n"
+    );
+
+    #[test]
+    fn test_public_key_output() {
+        let output = minisign_public_key_output(&keypair()).unwrap();
+        assert_eq!(output, "untrusted comment: minisign public key 76E658F18A3289A2\nRWSiiTKK8Vjmdq8Go+MpFxTk81bBnJsVzRlR7G5mYqp3vgdUfyiTgzQd\n");
+        assert_eq!(MinisignPublicKey::parse(&output).unwrap(), MinisignPublicKey::from_keypair(&keypair()).unwrap());
+        assert_eq!(
+            MinisignPublicKey::parse("RWSiiTKK8Vjmdq8Go+MpFxTk81bBnJsVzRlR7G5mYqp3vgdUfyiTgzQd").unwrap(),
+            MinisignPublicKey::from_keypair(&keypair()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        let untrusted = "signature from freepass key 76E658F18A3289A2";
+        let legacy_comment = minisign_default_trusted_comment(1500000000, "hello.txt", false);
+        let prehashed_comment = minisign_default_trusted_comment(1500000000, "hello.txt", true);
+        assert_eq!(minisign_sign(&keypair(), untrusted, &legacy_comment, b"hello world\n", false).unwrap(), LEGACY_SIGNATURE);
+        assert_eq!(minisign_sign(&keypair(), untrusted, &prehashed_comment, b"hello world\n", true).unwrap(), PREHASHED_SIGNATURE);
+        assert!(minisign_sign(&keypair(), untrusted, "two\nlines", b"hello world\n", true).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let pubkey = MinisignPublicKey::from_keypair(&keypair()).unwrap();
+        assert_eq!(minisign_verify(&pubkey, LEGACY_SIGNATURE, b"hello world\n").unwrap(), "timestamp:1500000000\tfile:hello.txt");
+        assert_eq!(minisign_verify(&pubkey, PREHASHED_SIGNATURE, b"hello world\n").unwrap(), "timestamp:1500000000\tfile:hello.txt\thashed");
+        match minisign_verify(&pubkey, PREHASHED_SIGNATURE, b"hello world!\n") {
+            Err(Error::SignatureVerificationFailed) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // The trusted comment can't be changed
+        match minisign_verify(&pubkey, &LEGACY_SIGNATURE.replace("hello.txt", "evil.txt"), b"hello world\n") {
+            Err(Error::SignatureVerificationFailed) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        let (other_pubkey, _) = ed25519::keypair_from_seed(&ed25519::Seed::from_slice(b"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb").unwrap());
+        let other_pubkey = MinisignPublicKey { key_id: minisign_key_id(&other_pubkey.0), key: other_pubkey };
+        match minisign_verify(&other_pubkey, LEGACY_SIGNATURE, b"hello world\n") {
+            Err(Error::MinisignWrongKey) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(minisign_verify(&pubkey, "hello world\n", b"hello world\n").is_err());
+    }
+}
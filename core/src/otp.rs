@@ -0,0 +1,279 @@
+use std::str;
+use base32;
+use byteorder::{BigEndian, WriteBytesExt};
+use crypto::sha1::Sha1;
+use crypto::sha2::{Sha256, Sha512};
+use secstr::SecStr;
+use result::{Error, Result};
+use util::{blake2b, hmac};
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum OtpAlgorithm {
+    SHA1,
+    SHA256,
+    SHA512,
+}
+
+/// HOTP (RFC 4226) / TOTP (RFC 6238) parameters, as found in `otpauth://` URIs.
+#[derive(PartialEq, Clone, Debug)]
+pub struct OtpParams {
+    pub secret: SecStr,
+    pub algorithm: OtpAlgorithm,
+    pub digits: u32,
+    pub period: u64,
+    pub counter: u64,
+}
+
+impl OtpParams {
+    pub fn new(secret: SecStr) -> OtpParams {
+        OtpParams {
+            secret: secret,
+            algorithm: OtpAlgorithm::SHA1,
+            digits: 6,
+            period: 30,
+            counter: 0,
+        }
+    }
+
+    /// Parses either an `otpauth://totp/...` / `otpauth://hotp/...` URI or just a base32 secret.
+    pub fn parse(data: &[u8]) -> Result<OtpParams> {
+        let text = str::from_utf8(data)?.trim();
+        if !text.starts_with("otpauth://") {
+            return Ok(OtpParams::new(decode_secret(text)?));
+        }
+        let query = text.splitn(2, '?').nth(1).ok_or(Error::InvalidOtpParameters)?;
+        let mut params = OtpParams::new(SecStr::new(Vec::new()));
+        let mut has_secret = false;
+        for pair in query.split('&') {
+            let mut kv = pair.splitn(2, '=');
+            let key = kv.next().unwrap_or("");
+            let value = percent_decode(kv.next().unwrap_or(""))?;
+            match key {
+                "secret" => {
+                    params.secret = decode_secret(&value)?;
+                    has_secret = true;
+                },
+                "algorithm" => {
+                    params.algorithm = match value.to_uppercase().as_ref() {
+                        "SHA1" => OtpAlgorithm::SHA1,
+                        "SHA256" => OtpAlgorithm::SHA256,
+                        "SHA512" => OtpAlgorithm::SHA512,
+                        _ => return Err(Error::InvalidOtpParameters),
+                    }
+                },
+                "digits" => params.digits = value.parse().map_err(|_| Error::InvalidOtpParameters)?,
+                "period" => params.period = value.parse().map_err(|_| Error::InvalidOtpParameters)?,
+                "counter" => params.counter = value.parse().map_err(|_| Error::InvalidOtpParameters)?,
+                _ => (), // issuer, image, etc.
+            }
+        }
+        if !has_secret || params.digits < 1 || params.digits > 9 || params.period < 1 {
+            return Err(Error::InvalidOtpParameters);
+        }
+        Ok(params)
+    }
+
+    /// Like `parse`, but rejects `otpauth://hotp/...` URIs: their codes depend on a counter, not on the time.
+    pub fn parse_totp(data: &[u8]) -> Result<OtpParams> {
+        let text = str::from_utf8(data)?.trim();
+        if text.get(0..14).map_or(false, |s| s.eq_ignore_ascii_case("otpauth://hotp")) {
+            return Err(Error::InvalidOtpParameters);
+        }
+        OtpParams::parse(data)
+    }
+}
+
+/// Length of derived TOTP secrets (the RFC 4226 recommendation of 160 bits).
+pub const DERIVED_SECRET_BYTES: usize = 20;
+
+/// The TOTP secret for a site seed. The secret is given to the site, so it's hashed
+/// instead of being a part of the seed (which is also the seed of passwords, keys, etc.)
+pub fn derived_secret(site_seed: &SecStr) -> SecStr {
+    SecStr::new(blake2b(site_seed.unsecure(), b"freepass.totp", DERIVED_SECRET_BYTES))
+}
+
+/// Everything needed to set up a derived TOTP secret on a site.
+pub struct TotpEnrollment {
+    /// base32 encoded, for typing into the site
+    pub secret: SecStr,
+    /// `otpauth://` URI, e.g. for a QR code
+    pub uri: SecStr,
+    pub code: String,
+    pub remaining: u64,
+}
+
+impl TotpEnrollment {
+    pub fn new(params: &OtpParams, label: &str, timestamp: u64) -> TotpEnrollment {
+        let (code, remaining) = totp(params, timestamp);
+        TotpEnrollment {
+            secret: encode_secret(params.secret.unsecure()),
+            uri: totp_uri(params, label),
+            code: code,
+            remaining: remaining,
+        }
+    }
+}
+
+pub fn encode_secret(secret: &[u8]) -> SecStr {
+    SecStr::from(base32::encode(base32::Alphabet::RFC4648 { padding: false }, secret))
+}
+
+/// Builds an `otpauth://totp/` URI, with the label also used as the issuer.
+pub fn totp_uri(params: &OtpParams, label: &str) -> SecStr {
+    let mut uri = format!(
+        "otpauth://totp/{}?secret={}&issuer={}",
+        percent_encode(label),
+        str::from_utf8(encode_secret(params.secret.unsecure()).unsecure()).unwrap(),
+        percent_encode(label)
+    );
+    if params.algorithm != OtpAlgorithm::SHA1 {
+        uri.push_str(&format!("&algorithm={:?}", params.algorithm));
+    }
+    if params.digits != 6 {
+        uri.push_str(&format!("&digits={}", params.digits));
+    }
+    if params.period != 30 {
+        uri.push_str(&format!("&period={}", params.period));
+    }
+    SecStr::from(uri)
+}
+
+/// Decodes a base32 secret, ignoring case, spaces, dashes and padding (sites show secrets in all kinds of ways).
+pub fn decode_secret(text: &str) -> Result<SecStr> {
+    let normalized = text.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-' && *c != '=')
+        .collect::<String>()
+        .to_uppercase();
+    match base32::decode(base32::Alphabet::RFC4648 { padding: false }, &normalized) {
+        Some(ref secret) if secret.len() > 0 => Ok(SecStr::new(secret.clone())),
+        _ => Err(Error::InvalidOtpParameters),
+    }
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> Result<String> {
+    let bytes = text.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = str::from_utf8(&bytes[i + 1..i + 3])?;
+            result.push(u8::from_str_radix(hex, 16).map_err(|_| Error::InvalidOtpParameters)?);
+            i += 3;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(result)?)
+}
+
+/// Generates the HOTP code for the counter (the `counter` in the params is ignored).
+pub fn hotp(params: &OtpParams, counter: u64) -> String {
+    let mut msg = Vec::with_capacity(8);
+    msg.write_u64::<BigEndian>(counter).unwrap();
+    let key = params.secret.unsecure();
+    let hash = match params.algorithm {
+        OtpAlgorithm::SHA1 => hmac(Sha1::new(), key, &msg),
+        OtpAlgorithm::SHA256 => hmac(Sha256::new(), key, &msg),
+        OtpAlgorithm::SHA512 => hmac(Sha512::new(), key, &msg),
+    };
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = ((hash[offset] as u32 & 0x7f) << 24) | ((hash[offset + 1] as u32) << 16) | ((hash[offset + 2] as u32) << 8) | (hash[offset + 3] as u32);
+    format!("{:0width$}", binary % 10u32.pow(params.digits), width = params.digits as usize)
+}
+
+/// Generates the TOTP code for the UNIX timestamp, returns it with the number of seconds it's still valid for.
+pub fn totp(params: &OtpParams, timestamp: u64) -> (String, u64) {
+    (hotp(params, timestamp / params.period), params.period - timestamp % params.period)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(secret: &str, algorithm: OtpAlgorithm) -> OtpParams {
+        let mut params = OtpParams::new(SecStr::from(secret));
+        params.algorithm = algorithm;
+        params.digits = 8;
+        params
+    }
+
+    #[test]
+    fn test_hotp_rfc4226() {
+        let params = OtpParams::new(SecStr::from("12345678901234567890"));
+        let codes = (0..10).map(|c| hotp(&params, c)).collect::<Vec<_>>();
+        assert_eq!(codes, vec!["755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583", "399871", "520489"]);
+    }
+
+    #[test]
+    fn test_totp_rfc6238() {
+        let sha1 = params("12345678901234567890", OtpAlgorithm::SHA1);
+        let sha256 = params("12345678901234567890123456789012", OtpAlgorithm::SHA256);
+        let sha512 = params("1234567890123456789012345678901234567890123456789012345678901234", OtpAlgorithm::SHA512);
+        let vectors = [
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (1111111111, "14050471", "67062674", "99943326"),
+            (1234567890, "89005924", "91819424", "93441116"),
+            (2000000000, "69279037", "90698825", "38618901"),
+            (20000000000, "65353130", "77737706", "47863826"),
+        ];
+        for &(time, code_sha1, code_sha256, code_sha512) in vectors.iter() {
+            assert_eq!(totp(&sha1, time).0, code_sha1);
+            assert_eq!(totp(&sha256, time).0, code_sha256);
+            assert_eq!(totp(&sha512, time).0, code_sha512);
+        }
+        assert_eq!(totp(&sha1, 59).1, 1);
+        assert_eq!(totp(&sha1, 60).1, 30);
+    }
+
+    #[test]
+    fn test_totp_uri() {
+        let params = OtpParams::new(SecStr::from("12345678901234567890"));
+        assert_eq!(
+            totp_uri(&params, "ACME Co:john@example.com"),
+            SecStr::from("otpauth://totp/ACME%20Co%3Ajohn%40example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=ACME%20Co%3Ajohn%40example.com")
+        );
+        let enrollment = TotpEnrollment::new(&params, "example.com", 59);
+        assert_eq!(enrollment.secret, SecStr::from("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+        assert_eq!(enrollment.code, "287082");
+        assert_eq!(enrollment.remaining, 1);
+    }
+
+    #[test]
+    fn test_derived_secret() {
+        let site_seed = SecStr::new((0..64).collect());
+        let secret = derived_secret(&site_seed);
+        assert_eq!(secret.unsecure().len(), DERIVED_SECRET_BYTES);
+        assert!(!site_seed.unsecure().starts_with(secret.unsecure()));
+        assert!(derived_secret(&SecStr::new((1..65).collect())) != secret);
+    }
+
+    #[test]
+    fn test_parse() {
+        let plain = OtpParams::parse(b"gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap();
+        assert_eq!(plain, OtpParams::new(SecStr::from("12345678901234567890")));
+        let uri = OtpParams::parse(b"otpauth://totp/ACME%20Co:john@example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=ACME%20Co&algorithm=SHA256&digits=8&period=60").unwrap();
+        assert_eq!(uri.secret, SecStr::from("12345678901234567890"));
+        assert_eq!(uri.algorithm, OtpAlgorithm::SHA256);
+        assert_eq!(uri.digits, 8);
+        assert_eq!(uri.period, 60);
+        let hotp_uri = OtpParams::parse(b"otpauth://hotp/Example?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&counter=5").unwrap();
+        assert_eq!(hotp_uri.counter, 5);
+        assert!(OtpParams::parse_totp(b"otpauth://hotp/Example?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&counter=5").is_err());
+        assert_eq!(OtpParams::parse_totp(b"otpauth://totp/Example?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap().secret, uri.secret);
+        assert!(OtpParams::parse(b"otpauth://totp/Example?issuer=Nope").is_err());
+        let roundtrip = OtpParams::parse(totp_uri(&uri, "ACME Co").unsecure()).unwrap();
+        assert_eq!(roundtrip, uri);
+        assert!(OtpParams::parse(b"not base32!").is_err());
+    }
+}
@@ -14,6 +14,8 @@ pub enum Error {
     InappropriateFormat,
     SeedGenerationError,
     DecryptionError,
+    OuterAuthenticationFailed,
+    UnsupportedVaultVersion,
     CodecError(serde_cbor::Error),
     StringCodecError(string::FromUtf8Error),
     StrCodecError(str::Utf8Error),
@@ -22,10 +24,24 @@ pub enum Error {
     RandError(rand::Error),
     OtherError(io::Error),
     DataError,
+    InvalidOtpParameters,
+    InvalidSqrlUrl,
+    InvalidMnemonic,
     EntryNotFound,
     NotImplemented,
     NotAvailableOnPlatform,
     SSHAgentSocketNotFound,
+    SSHAgentRefused,
+    SSHAgentProtocolError,
+    InvalidSshPublicKey,
+    InvalidSignifyFormat,
+    SignifyWrongKey,
+    SignatureVerificationFailed,
+    InvalidMinisignFormat,
+    MinisignWrongKey,
+    InvalidAgeFormat,
+    AgeNoMatchingIdentity,
+    MergeConflictOutdated,
 }
 
 impl From<serde_cbor::Error> for Error {
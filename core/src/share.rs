@@ -0,0 +1,124 @@
+//! Sending entries to other freepass users, sealed to one of their derived Ed25519 keys.
+
+use std::collections::btree_map::BTreeMap;
+use serde_cbor;
+use secstr::SecStr;
+use sodiumoxide::crypto::sign::ed25519;
+use sodiumoxide::crypto::sealedbox;
+use data::*;
+use output::*;
+use result::{Error, Result};
+
+#[derive(Serialize, Deserialize)]
+struct SharedEntry {
+    name: String,
+    entry: Entry,
+}
+
+/// Reads the recipient's public key: an `ssh-ed25519` line or a signify public key file.
+pub fn parse_share_recipient(text: &str) -> Result<ed25519::PublicKey> {
+    if text.trim().starts_with("ssh-ed25519 ") {
+        let (blob, _) = parse_ssh_public_key(text)?;
+        let mut reader = &blob[..];
+        let _key_type = read_ssh_string(&mut reader)?;
+        ed25519::PublicKey::from_slice(&read_ssh_string(&mut reader)?).ok_or(Error::InvalidSshPublicKey)
+    } else {
+        Ok(SignifyPublicKey::parse(text.as_bytes())?.key)
+    }
+}
+
+/// Replaces derived passwords with stored ones, since the recipient can't derive them.
+/// Derived keys can't be shared.
+pub fn snapshot_entry(entry_name: &str, master_key: &SecStr, entry: &Entry) -> Result<Entry> {
+    let mut fields = BTreeMap::new();
+    for (field_name, field) in entry.fields.iter() {
+        fields.insert(field_name.to_owned(), snapshot_derived_field(entry_name, master_key, field)?);
+    }
+    Ok(Entry { fields: fields })
+}
+
+/// Encrypts the entry to the recipient's key (converted to Curve25519) in a sealed box.
+pub fn share_entry(recipient: &ed25519::PublicKey, entry_name: &str, entry: &Entry) -> Result<Vec<u8>> {
+    if entry.fields.values().any(|f| if let &Field::Derived { .. } = f { true } else { false }) {
+        return Err(Error::InappropriateFormat);
+    }
+    let box_pubkey = ed25519::to_curve25519_pk(recipient).map_err(|_| Error::InappropriateFormat)?;
+    let plaintext = SecStr::new(serde_cbor::to_vec(&SharedEntry { name: entry_name.to_owned(), entry: entry.clone() })?);
+    Ok(sealedbox::seal(plaintext.unsecure(), &box_pubkey))
+}
+
+/// Opens a sealed box made by `share_entry` with an SSH or signify key field, returns the entry name and the entry.
+pub fn receive_entry(keypair: &Output, data: &[u8]) -> Result<(String, Entry)> {
+    match *keypair {
+        Output::Ed25519Keypair(Ed25519Usage::SSH, ref pubkey, ref seckey) |
+        Output::Ed25519Keypair(Ed25519Usage::Signify, ref pubkey, ref seckey) => {
+            let box_pubkey = ed25519::to_curve25519_pk(pubkey).map_err(|_| Error::InappropriateFormat)?;
+            let box_seckey = ed25519::to_curve25519_sk(seckey).map_err(|_| Error::InappropriateFormat)?;
+            let plaintext = SecStr::new(sealedbox::open(data, &box_pubkey, &box_seckey).map_err(|_| Error::DecryptionError)?);
+            let shared: SharedEntry = serde_cbor::from_slice(plaintext.unsecure())?;
+            Ok((shared.name, shared.entry))
+        },
+        _ => Err(Error::InappropriateFormat),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair(usage: Ed25519Usage, seed: &[u8]) -> Output {
+        let (pubkey, seckey) = ed25519::keypair_from_seed(&ed25519::Seed::from_slice(seed).unwrap());
+        Output::Ed25519Keypair(usage, pubkey, seckey)
+    }
+
+    fn entry() -> Entry {
+        let mut entry = Entry::default();
+        entry.fields.insert("password".to_owned(), Field::Stored { data: SecStr::from("hunter2"), usage: StoredUsage::Password });
+        entry
+    }
+
+    #[test]
+    fn test_parse_share_recipient() {
+        let ssh = keypair(Ed25519Usage::SSH, b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        let signify = keypair(Ed25519Usage::Signify, b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        if let Output::Ed25519Keypair(_, pubkey, _) = ssh {
+            assert_eq!(parse_share_recipient(&ssh_public_key_output(&ssh, "someone").unwrap()).unwrap(), pubkey);
+            assert_eq!(parse_share_recipient(&signify_public_key_output(&signify, "someone").unwrap()).unwrap(), pubkey);
+        }
+        assert!(parse_share_recipient("hello world").is_err());
+    }
+
+    #[test]
+    fn test_share_receive() {
+        let recipient = keypair(Ed25519Usage::SSH, b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        let recipient_pubkey = if let Output::Ed25519Keypair(_, pubkey, _) = recipient { pubkey } else { unreachable!() };
+        let sealed = share_entry(&recipient_pubkey, "example.com", &entry()).unwrap();
+        assert_eq!(receive_entry(&recipient, &sealed).unwrap(), ("example.com".to_owned(), entry()));
+        // The same key used for signify works too
+        let signify = keypair(Ed25519Usage::Signify, b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        assert_eq!(receive_entry(&signify, &sealed).unwrap().1, entry());
+        match receive_entry(&keypair(Ed25519Usage::SSH, b"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"), &sealed) {
+            Err(Error::DecryptionError) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(receive_entry(&keypair(Ed25519Usage::SQRL, b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"), &sealed).is_err());
+    }
+
+    #[test]
+    fn test_derived_fields() {
+        let master_key = SecStr::from("not really a master key, but good enough for testing");
+        let mut entry = entry();
+        entry.fields.insert("derived".to_owned(), Field::Derived { counter: 1, site_name: None, usage: DerivedUsage::Password(PasswordTemplate::Long) });
+        let (pubkey, _) = ed25519::keypair_from_seed(&ed25519::Seed::from_slice(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap());
+        assert!(share_entry(&pubkey, "example.com", &entry).is_err());
+        let snapshot = snapshot_entry("example.com", &master_key, &entry).unwrap();
+        let expected = process_output("example.com", &master_key, &entry.fields["derived"]).unwrap();
+        match (&snapshot.fields["derived"], expected) {
+            (&Field::Stored { ref data, usage: StoredUsage::Password }, Output::PrivateText(ref s)) => assert_eq!(data, s),
+            _ => panic!("Not snapshotted"),
+        }
+        assert!(share_entry(&pubkey, "example.com", &snapshot).is_ok());
+        entry.fields.insert("key".to_owned(), Field::Derived { counter: 1, site_name: None, usage: DerivedUsage::Ed25519Key(Ed25519Usage::SSH) });
+        assert!(snapshot_entry("example.com", &master_key, &entry).is_err());
+    }
+}
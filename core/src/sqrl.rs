@@ -0,0 +1,160 @@
+use base64;
+use crypto::sha2::Sha256;
+use sodiumoxide::crypto::sign::ed25519;
+use secstr::SecStr;
+use data::Ed25519Usage;
+use output::Output;
+use result::{Error, Result};
+use util::hmac;
+
+/// A parsed `sqrl://` login URL.
+#[derive(PartialEq, Clone, Debug)]
+pub struct SqrlUrl {
+    pub url: String,
+    /// What the site key is derived from: the host, plus the first `x` characters of the path if requested.
+    pub domain: String,
+    /// Where the client request is POSTed to.
+    pub https_url: String,
+}
+
+impl SqrlUrl {
+    pub fn parse(url: &str) -> Result<SqrlUrl> {
+        let url = url.trim();
+        if !url.get(0..7).map_or(false, |s| s.eq_ignore_ascii_case("sqrl://")) {
+            return Err(Error::InvalidSqrlUrl);
+        }
+        let rest = &url[7..];
+        let authority_end = rest.find(|c| c == '/' || c == '?').unwrap_or(rest.len());
+        let authority = &rest[0..authority_end];
+        let path_and_query = &rest[authority_end..];
+        // Neither the user info nor the port are part of the domain used for the key
+        let host_port = authority.rsplitn(2, '@').next().unwrap_or("");
+        let host = host_port.splitn(2, ':').next().unwrap_or("").to_lowercase();
+        if host.len() < 1 {
+            return Err(Error::InvalidSqrlUrl);
+        }
+        let mut parts = path_and_query.splitn(2, '?');
+        let path = parts.next().unwrap_or("");
+        let query = parts.next().unwrap_or("");
+        let extension = match query.split('&').find(|p| p.starts_with("x=")) {
+            Some(p) => p[2..].parse::<usize>().map_err(|_| Error::InvalidSqrlUrl)?,
+            None => 0,
+        };
+        if extension > path.len() || !path.is_char_boundary(extension) {
+            return Err(Error::InvalidSqrlUrl);
+        }
+        Ok(SqrlUrl {
+            url: url.to_owned(),
+            domain: host + &path[0..extension],
+            https_url: format!("https://{}", rest),
+        })
+    }
+}
+
+/// A signed client request, to be POSTed as `application/x-www-form-urlencoded`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct SqrlRequest {
+    pub client: String,
+    pub server: String,
+    pub ids: String,
+}
+
+impl SqrlRequest {
+    pub fn to_post_body(&self) -> String {
+        format!("client={}&server={}&ids={}", self.client, self.server, self.ids)
+    }
+}
+
+fn base64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// The identity master key is the seed of the derived Ed25519 key
+/// (libsodium secret keys are the seed followed by the public key).
+fn identity_master_key(keypair: &Output) -> Result<SecStr> {
+    if let &Output::Ed25519Keypair(Ed25519Usage::SQRL, _, ed25519::SecretKey(ref seckey_bytes)) = keypair {
+        Ok(SecStr::new(seckey_bytes[0..ed25519::SEEDBYTES].to_vec()))
+    } else {
+        Err(Error::InappropriateFormat)
+    }
+}
+
+/// Derives the per-site key: the Ed25519 key with the seed HMAC-SHA256(identity master key, domain).
+pub fn sqrl_site_keypair(keypair: &Output, url: &SqrlUrl) -> Result<(ed25519::PublicKey, ed25519::SecretKey)> {
+    let imk = identity_master_key(keypair)?;
+    let site_seed = SecStr::new(hmac(Sha256::new(), imk.unsecure(), url.domain.as_bytes()));
+    let edseed = ed25519::Seed::from_slice(site_seed.unsecure())
+        .ok_or(Error::WrongDerivedKeyLength)?;
+    Ok(ed25519::keypair_from_seed(&edseed))
+}
+
+/// Builds and signs the first client request for a login (e.g. `query` or `ident`).
+pub fn sqrl_client_request(keypair: &Output, url: &SqrlUrl, command: &str) -> Result<SqrlRequest> {
+    let (ed25519::PublicKey(pubkey_bytes), seckey) = sqrl_site_keypair(keypair, url)?;
+    let client = base64url(format!("ver=1\r\ncmd={}\r\nidk={}\r\n", command, base64url(&pubkey_bytes)).as_bytes());
+    let server = base64url(url.url.as_bytes());
+    let ed25519::Signature(sig_bytes) = ed25519::sign_detached((client.clone() + &server).as_bytes(), &seckey);
+    Ok(SqrlRequest { client: client, server: server, ids: base64url(&sig_bytes[..]) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The expected keys and signatures were computed independently (HMAC-SHA256 and Ed25519 from Python's
+    // hmac and cryptography packages), with the RFC 8032 test 1 secret key as the identity master key.
+    fn keypair() -> Output {
+        let imk = [
+            0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec, 0x2c, 0xc4,
+            0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03, 0x1c, 0xae, 0x7f, 0x60,
+        ];
+        let (pubkey, seckey) = ed25519::keypair_from_seed(&ed25519::Seed::from_slice(&imk).unwrap());
+        Output::Ed25519Keypair(Ed25519Usage::SQRL, pubkey, seckey)
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let url = SqrlUrl::parse("sqrl://www.GRC.com/sqrl?nut=P2Kr_4GB49GrwAFMKQ1z5g").unwrap();
+        assert_eq!(url.domain, "www.grc.com");
+        assert_eq!(url.https_url, "https://www.GRC.com/sqrl?nut=P2Kr_4GB49GrwAFMKQ1z5g");
+        let extended = SqrlUrl::parse("sqrl://user@example.com:8080/demo/cli.sqrl?x=5&nut=oOB4QOFJux5Z").unwrap();
+        assert_eq!(extended.domain, "example.com/demo");
+        assert!(SqrlUrl::parse("https://example.com/sqrl?nut=1").is_err());
+        assert!(SqrlUrl::parse("sqrl://example.com/a?x=10&nut=1").is_err());
+    }
+
+    #[test]
+    fn test_parse_non_ascii_url() {
+        assert!(SqrlUrl::parse("sqrl:/é/example.com").is_err());
+        assert!(SqrlUrl::parse("ß").is_err());
+        let url = SqrlUrl::parse("sqrl://Bücher.example/sqrl?nut=1").unwrap();
+        assert_eq!(url.domain, "bücher.example");
+        assert!(SqrlUrl::parse("sqrl://example.com/é?x=2&nut=1").is_err());
+    }
+
+    #[test]
+    fn test_site_keypair() {
+        let grc = SqrlUrl::parse("sqrl://www.grc.com/sqrl?nut=1").unwrap();
+        let (ed25519::PublicKey(grc_pubkey), _) = sqrl_site_keypair(&keypair(), &grc).unwrap();
+        assert_eq!(base64url(&grc_pubkey), "QBYa7DQ5WSc55YTAfQDjAzvcrQacNZrNm1WnF38R09Y");
+        let extended = SqrlUrl::parse("sqrl://example.com/demo/cli.sqrl?x=5&nut=1").unwrap();
+        let (ed25519::PublicKey(extended_pubkey), _) = sqrl_site_keypair(&keypair(), &extended).unwrap();
+        assert_eq!(base64url(&extended_pubkey), "ZXHVpFqquT9FX_oW5Iv56ulWLXi2Fmt38cMuELtsu4U");
+        let non_ascii = SqrlUrl::parse("sqrl://bücher.example/sqrl?nut=1").unwrap();
+        let (ed25519::PublicKey(non_ascii_pubkey), _) = sqrl_site_keypair(&keypair(), &non_ascii).unwrap();
+        assert_eq!(base64url(&non_ascii_pubkey), "-n21Jvg4ndUehtIKfPKVpHW9ivRtLaqgRDpF5mDqIh8");
+    }
+
+    #[test]
+    fn test_client_request() {
+        let url = SqrlUrl::parse("sqrl://www.grc.com/sqrl?nut=P2Kr_4GB49GrwAFMKQ1z5g").unwrap();
+        assert_eq!(
+            sqrl_client_request(&keypair(), &url, "query").unwrap(),
+            SqrlRequest {
+                client: "dmVyPTENCmNtZD1xdWVyeQ0KaWRrPVFCWWE3RFE1V1NjNTVZVEFmUURqQXp2Y3JRYWNOWnJObTFXbkYzOFIwOVkNCg".to_owned(),
+                server: "c3FybDovL3d3dy5ncmMuY29tL3Nxcmw_bnV0PVAyS3JfNEdCNDlHcndBRk1LUTF6NWc".to_owned(),
+                ids: "cswP4FN9GpPEDX3VZF9qaj_3zd2uVSo8E4MgcV5Y2NKpSIPYacLbUW-kvjx-Sc4q_cTic7Dt-b5K6LZedeUjBQ".to_owned(),
+            }
+        );
+    }
+}
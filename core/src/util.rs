@@ -1,5 +1,8 @@
 extern crate libsodium_sys;
 use libc::size_t;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
 use data::{StoredUsage, DerivedUsage, PasswordTemplate, Ed25519Usage};
 
 pub fn blake2b(key: &[u8], msg: &[u8], len: usize) -> Vec<u8> {
@@ -11,9 +14,17 @@ pub fn blake2b(key: &[u8], msg: &[u8], len: usize) -> Vec<u8> {
     dst
 }
 
+pub fn hmac<D: Digest>(digest: D, key: &[u8], msg: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(digest, key);
+    hmac.input(msg);
+    hmac.result().code().to_vec()
+}
+
 pub fn guess_usage_stored(field_name: &str) -> StoredUsage {
     let fname = field_name.to_lowercase();
-    if fname.contains("pass") || fname.contains("pin") || fname.contains("code") {
+    if fname.contains("totp") || fname.contains("2fa") || fname.contains("otpauth") {
+        StoredUsage::Totp
+    } else if fname.contains("pass") || fname.contains("pin") || fname.contains("code") {
         StoredUsage::Password
     } else {
         StoredUsage::Text
@@ -22,7 +33,17 @@ pub fn guess_usage_stored(field_name: &str) -> StoredUsage {
 
 pub fn guess_usage_derived(field_name: &str) -> DerivedUsage {
     let fname = field_name.to_lowercase();
-    if fname.contains("sign") {
+    if fname.contains("totp") || fname.contains("2fa") {
+        DerivedUsage::TotpSecret
+    } else if fname.contains("x25519") || fname.split(|c: char| !c.is_alphanumeric()).any(|w| w == "age") {
+        DerivedUsage::X25519Key
+    } else if fname.contains("wireguard") || fname.starts_with("wg") {
+        DerivedUsage::WireGuard
+    } else if fname.contains("pgp") || fname.contains("gpg") {
+        DerivedUsage::OpenPgp
+    } else if fname.contains("minisign") {
+        DerivedUsage::Ed25519Key(Ed25519Usage::Minisign)
+    } else if fname.contains("sign") {
         DerivedUsage::Ed25519Key(Ed25519Usage::Signify)
     } else if fname.contains("key") || fname.contains("ssh") {
         DerivedUsage::Ed25519Key(Ed25519Usage::SSH)
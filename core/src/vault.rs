@@ -1,14 +1,34 @@
 use std::iter::Iterator;
+use chrono::{DateTime, UTC};
 use result::Result;
-use data::{Entry, EntryMetadata};
+use data::{Entry, EntryMetadata, Tombstone};
 
 pub trait Vault {
     fn len(&self) -> usize;
     fn entry_names<'a>(&'a self) -> Box<Iterator<Item = &'a String> + 'a>;
     fn get_entry(&self, name: &str) -> Result<(Entry, EntryMetadata)>;
+
+    /// The tombstone of the removed entry with this name, if the vault remembers it.
+    fn tombstone(&self, _name: &str) -> Option<Tombstone> {
+        None
+    }
 }
 
 pub trait WritableVault {
     fn put_entry(&mut self, name: &str, entry: &Entry, metadata: &mut EntryMetadata) -> Result<()>;
     fn remove_entry(&mut self, name: &str);
+
+    /// Stores an entry merged from another vault as it is. Unlike `put_entry`, this isn't a new change,
+    /// so `updated_at` and the field versions are kept, and older tombstones elsewhere still win over it.
+    fn put_merged_entry(&mut self, name: &str, entry: &Entry, metadata: &EntryMetadata) -> Result<()>;
+
+    /// Removes an entry that was removed in another vault, keeping the time it was removed there.
+    fn remove_merged_entry(&mut self, name: &str, deleted_at: DateTime<UTC>);
+
+    /// Moves the entry to a new name. It keeps its ID, and the time of the rename is recorded for merging.
+    fn rename_entry(&mut self, old_name: &str, new_name: &str, entry: &Entry, metadata: &mut EntryMetadata) -> Result<()> {
+        metadata.renamed_at = Some(UTC::now());
+        self.remove_entry(old_name);
+        self.put_entry(new_name, entry, metadata)
+    }
 }
@@ -0,0 +1,88 @@
+//! [WireGuard](https://www.wireguard.com) keys derived from the site seed.
+
+use base64;
+use secstr::SecStr;
+use sodiumoxide::crypto::scalarmult::curve25519;
+use output::Output;
+use result::{Error, Result};
+use util::blake2b;
+
+/// Turns the site seed into a private key like `wg genkey` makes them.
+/// The seed is hashed first, so the key isn't the same as the `X25519Key` of the seed.
+pub fn wireguard_keypair(seed: &SecStr) -> Result<Output> {
+    if seed.unsecure().len() != curve25519::SCALARBYTES {
+        return Err(Error::WrongDerivedKeyLength);
+    }
+    let mut private_key = SecStr::new(blake2b(seed.unsecure(), b"freepass.wireguard", curve25519::SCALARBYTES));
+    {
+        let bytes = private_key.unsecure_mut();
+        bytes[0] &= 248;
+        bytes[31] &= 127;
+        bytes[31] |= 64;
+    }
+    let private_key = curve25519::Scalar::from_slice(private_key.unsecure()).ok_or(Error::WrongDerivedKeyLength)?;
+    Ok(Output::WireGuardKeypair(curve25519::scalarmult_base(&private_key), private_key))
+}
+
+/// The public key, like `wg pubkey` prints it.
+pub fn wireguard_public_key_output(keypair: &Output) -> Result<String> {
+    if let &Output::WireGuardKeypair(ref pubkey, _) = keypair {
+        Ok(base64::encode(&pubkey[..]))
+    } else {
+        Err(Error::InappropriateFormat)
+    }
+}
+
+pub fn wireguard_private_key_output(keypair: &Output) -> Result<SecStr> {
+    if let &Output::WireGuardKeypair(_, ref seckey) = keypair {
+        Ok(SecStr::from(base64::encode(&seckey[..])))
+    } else {
+        Err(Error::InappropriateFormat)
+    }
+}
+
+/// An `[Interface]` section for a `wg-quick` config file.
+pub fn wireguard_interface_config(keypair: &Output, address: Option<&str>, listen_port: Option<u16>) -> Result<SecStr> {
+    let mut config = format!(
+        "[Interface]\n# PublicKey = {}\nPrivateKey = {}\n",
+        wireguard_public_key_output(keypair)?,
+        String::from_utf8(wireguard_private_key_output(keypair)?.unsecure().to_vec())?
+    );
+    if let Some(address) = address {
+        config.push_str(&format!("Address = {}\n", address));
+    }
+    if let Some(listen_port) = listen_port {
+        config.push_str(&format!("ListenPort = {}\n", listen_port));
+    }
+    Ok(SecStr::from(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let keypair = wireguard_keypair(&SecStr::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")).unwrap();
+        assert_eq!(wireguard_private_key_output(&keypair).unwrap(), SecStr::from("mNcNGa4NQueNYQnNRxYXgo5rkEjYiOF91/hQClQZGEc="));
+        assert_eq!(wireguard_public_key_output(&keypair).unwrap(), "SAmUGo7Uivz4hkTtLo4xJAYMUB1bmRK+D/gh3wRJQB4=");
+        assert!(wireguard_keypair(&SecStr::from("too short")).is_err());
+    }
+
+    #[test]
+    fn test_interface_config() {
+        let keypair = wireguard_keypair(&SecStr::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")).unwrap();
+        assert_eq!(wireguard_interface_config(&keypair, Some("10.0.0.1/24"), Some(51820)).unwrap(), SecStr::from(concat!(
+            "[Interface]\n",
+            "# PublicKey = SAmUGo7Uivz4hkTtLo4xJAYMUB1bmRK+D/gh3wRJQB4=\n",
+            "PrivateKey = mNcNGa4NQueNYQnNRxYXgo5rkEjYiOF91/hQClQZGEc=\n",
+            "Address = 10.0.0.1/24\n",
+            "ListenPort = 51820\n"
+        )));
+        assert_eq!(wireguard_interface_config(&keypair, None, None).unwrap(), SecStr::from(concat!(
+            "[Interface]\n",
+            "# PublicKey = SAmUGo7Uivz4hkTtLo4xJAYMUB1bmRK+D/gh3wRJQB4=\n",
+            "PrivateKey = mNcNGa4NQueNYQnNRxYXgo5rkEjYiOF91/hQClQZGEc=\n"
+        )));
+    }
+}